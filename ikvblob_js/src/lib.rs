@@ -1,7 +1,11 @@
 #![allow(dead_code)]
 
 use js_sys::Promise;
-use ikvblob::{fileformat_read::IkvblobView, memory_view::Memory, multihash::Multihash};
+use ikvblob::{
+    fileformat_read::IkvblobView,
+    memory_view::{Memory, MemoryError},
+    multihash::Multihash,
+};
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen]
@@ -30,30 +34,47 @@ impl JsCallbackMemory {
     }
 }
 
+/// A callback that throws is a bug in the caller, so only a rejected promise is transient.
+fn backend_error(what: &str, e: JsValue, transient: bool) -> MemoryError {
+    MemoryError::Backend {
+        message: format!("{}: {:?}", what, e),
+        transient,
+    }
+}
+
 impl Memory for JsCallbackMemory {
-    async fn read_slice(&self, range: std::ops::Range<usize>) -> Vec<u8> {
+    async fn read_slice(&self, range: std::ops::Range<usize>) -> Result<Vec<u8>, MemoryError> {
         let this = JsValue::null();
         let start = range.start as i64;
         let end = range.end as i64;
         let promise_jsvalue = self
             .read_callback
             .call2(&this, &JsValue::from(start), &JsValue::from(end))
-            .unwrap();
+            .map_err(|e| backend_error("read_callback threw", e, false))?;
         let result = wasm_bindgen_futures::JsFuture::from(Promise::from(promise_jsvalue))
             .await
-            .unwrap();
+            .map_err(|e| backend_error("read_callback rejected", e, true))?;
         let bytes = js_sys::Uint8Array::new(&result).to_vec();
-        bytes
+        Ok(bytes)
     }
 
-    async fn len(&self) -> usize {
+    async fn len(&self) -> Result<usize, MemoryError> {
         let this = JsValue::null();
-        let promise_jsvalue = self.len_callback.call0(&this).unwrap();
+        let promise_jsvalue = self
+            .len_callback
+            .call0(&this)
+            .map_err(|e| backend_error("len_callback threw", e, false))?;
         let result = wasm_bindgen_futures::JsFuture::from(Promise::from(promise_jsvalue))
             .await
-            .unwrap();
-        let sz = result.as_f64().map(|f| f as usize).unwrap();
-        sz
+            .map_err(|e| backend_error("len_callback rejected", e, true))?;
+        let sz = result
+            .as_f64()
+            .map(|f| f as usize)
+            .ok_or_else(|| MemoryError::Backend {
+                message: format!("len_callback returned a non-number: {:?}", result),
+                transient: false,
+            })?;
+        Ok(sz)
     }
 }
 
@@ -86,7 +107,10 @@ impl IkvblobReader {
     }
 
     pub async fn lookup_key(&self, key: &[u8]) -> Result<Option<Uint8ArrayType>, JsError> {
-        let hash = Multihash::wrap(1, key.try_into().unwrap());
+        let digest = key
+            .try_into()
+            .map_err(|_| JsError::new(&format!("Key must be 32 bytes, got {}", key.len())))?;
+        let hash = Multihash::wrap(1, digest);
         let res = self.view.lookup(&hash).await;
         let res_vec = res.map_err(|e| JsError::new(&format!("Error: {}", e)))?;
         match res_vec {
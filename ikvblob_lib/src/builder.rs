@@ -0,0 +1,803 @@
+//! High-level archive construction
+//!
+//! `IkvblobBuilder` turns `(key, value)` pairs into a finished archive in one call. It trains
+//! the compression dictionary, compresses the values, builds the index and writes the file, i.e.
+//! everything that otherwise takes `construction`, a `StaticCuckooTable` and
+//! `write_combined_file`.
+//!
+//! Only the keys and value addresses have to fit in memory. The values are kept in memory
+//! too by default, but with `IkvblobBuilder::spool_dir` they are appended to a temporary file as
+//! they are compressed, and copied into the archive at the end. For key sets that don't fit in
+//! memory either, `IkvblobBuilder::index_partitions` splits the index into regions that are
+//! built from on-disk runs one at a time. With `format_version(2)`, and always with a partitioned
+//! index, the values are written straight to the destination instead, which doesn't have to be
+//! seekable.
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use std::collections::BTreeMap;
+//!
+//! use ikvblob::{builder::{Compression, IkvblobBuilder}, multihash::Multihash};
+//!
+//! let pairs = (0..1000u32).map(|i| {
+//!     let mut digest = [0; 32];
+//!     digest[..4].copy_from_slice(&i.to_le_bytes());
+//!     (Multihash::<32>::wrap(1, digest), format!("value {}", i).into_bytes())
+//! });
+//! let mut archive = Vec::new();
+//! IkvblobBuilder::new()
+//!     .compression(Compression::zstd())
+//!     .metadata(BTreeMap::from([("source", "example")]))
+//!     .build_from_pairs(pairs, &mut archive)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    borrow::Cow,
+    error::Error,
+    io::{self, BufReader, BufWriter, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use rand::SeedableRng;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    construction::{build_compressor, COMPRESSION_LEVEL},
+    cuckoo::{CuckooIndex, ParallelBuild, PartitionRuns, StaticCuckooTable},
+    fileformat_write::{
+        write_combined_file, StreamingWriter, FILE_FORMAT_VERSION, FOOTER_FORMAT_VERSION,
+        PARTITIONED_FORMAT_VERSION,
+    },
+    multihash::Multihash,
+    utils,
+};
+
+/// Number of hash functions of the index, see the README.
+const NUM_HASHERS: usize = 2;
+
+/// Number of values compressed together, in parallel, before they are written out.
+const VALUE_CHUNK_SIZE: usize = 4096;
+
+/// Bucket sizes `IkvblobBuilder::bucket_size` accepts.
+pub const SUPPORTED_BUCKET_SIZES: &[usize] = &[1, 2, 4, 8, 16];
+
+type Key = Multihash<32>;
+type Entry = (Key, (u64, u64));
+type Index<const BS: usize> = Box<dyn CuckooIndex<BS, NUM_HASHERS, Key, (u64, u64)>>;
+
+/// How `IkvblobBuilder` compresses values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// Zstd with a dictionary trained on up to `sample_count` values, which is stored in the
+    /// metadata of the archive. Readers load the whole dictionary before their first lookup.
+    Zstd {
+        max_dict_size: usize,
+        sample_count: usize,
+    },
+}
+
+impl Compression {
+    /// Zstd with a dictionary of up to 1 MiB, trained on up to 100 000 values.
+    pub fn zstd() -> Self {
+        Compression::Zstd {
+            max_dict_size: 1024 * 1024,
+            sample_count: 100_000,
+        }
+    }
+}
+
+/// Builds an archive from `(key, value)` pairs, see the module documentation.
+///
+/// Keys and value addresses are kept in memory to build the index unless it is partitioned, and
+/// so are the values unless they are spooled to disk. If a key appears more than once, its last
+/// value wins.
+#[derive(Debug, Clone)]
+pub struct IkvblobBuilder<MD = ()> {
+    compression: Compression,
+    load_factor: f32,
+    bucket_size: usize,
+    spool_dir: Option<PathBuf>,
+    format_version: u64,
+    index_partitions: usize,
+    index_build: ParallelBuild,
+    metadata: MD,
+}
+
+impl Default for IkvblobBuilder {
+    fn default() -> Self { Self::new() }
+}
+
+impl IkvblobBuilder {
+    /// A builder for uncompressed archives with the index layout described in the README:
+    /// buckets of 8 entries, filled to 5/6, and no user metadata.
+    pub fn new() -> Self {
+        IkvblobBuilder {
+            compression: Compression::None,
+            load_factor: 5.0 / 6.0,
+            bucket_size: 8,
+            spool_dir: None,
+            format_version: FILE_FORMAT_VERSION,
+            index_partitions: 1,
+            index_build: ParallelBuild::Deterministic,
+            metadata: (),
+        }
+    }
+}
+
+impl<MD: Serialize> IkvblobBuilder<MD> {
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Fraction of the index slots that are filled. Higher values make the index smaller, but
+    /// building fails if the keys can't be placed. With buckets of one entry, this must be below
+    /// 1/2.
+    pub fn load_factor(mut self, load_factor: f32) -> Self {
+        self.load_factor = load_factor;
+        self
+    }
+
+    /// Number of entries per index bucket, one of `SUPPORTED_BUCKET_SIZES`. A lookup reads up to
+    /// two buckets.
+    pub fn bucket_size(mut self, bucket_size: usize) -> Self {
+        self.bucket_size = bucket_size;
+        self
+    }
+
+    /// Writes the compressed values to a temporary file in `dir`, e.g. `std::env::temp_dir()`,
+    /// instead of keeping them in memory. The file needs as much space as the values, and is
+    /// deleted once the archive is written, even if building fails.
+    pub fn spool_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.spool_dir = Some(dir.into());
+        self
+    }
+
+    /// File format version to write. Version 1, the default, puts the header, metadata and index
+    /// in front of the values, so the values are kept in memory or spooled until the index is
+    /// done. Version 2 writes the values first and the rest in a footer, in a single pass without
+    /// spooling, which needs a reader that supports it. Version 3 is version 2 with a partitioned
+    /// index, see `index_partitions`.
+    pub fn format_version(mut self, version: u64) -> Self {
+        self.format_version = version;
+        self
+    }
+
+    /// Splits the index into `partitions` regions, and builds them one at a time, so that only a
+    /// region's share of the keys has to fit in memory. Until then, the keys and value addresses
+    /// are written to one temporary file per region, in `spool_dir` or else the system's
+    /// temporary directory. Defaults to 1, which doesn't split the index.
+    ///
+    /// A partitioned index is written in format version 3, which this switches to, so that
+    /// readers that don't support it reject the archive rather than miss keys.
+    pub fn index_partitions(mut self, partitions: usize) -> Self {
+        self.index_partitions = partitions;
+        if partitions > 1 {
+            self.format_version = PARTITIONED_FORMAT_VERSION;
+        }
+        self
+    }
+
+    /// How the index, or each of its regions, is built on several threads. The default,
+    /// `ParallelBuild::Deterministic`, gives the same archive as building it on one thread.
+    pub fn index_build(mut self, build: ParallelBuild) -> Self {
+        self.index_build = build;
+        self
+    }
+
+    /// User metadata to store in the archive, see `write_combined_file`.
+    pub fn metadata<T: Serialize>(self, metadata: T) -> IkvblobBuilder<T> {
+        IkvblobBuilder {
+            compression: self.compression,
+            load_factor: self.load_factor,
+            bucket_size: self.bucket_size,
+            spool_dir: self.spool_dir,
+            format_version: self.format_version,
+            index_partitions: self.index_partitions,
+            index_build: self.index_build,
+            metadata,
+        }
+    }
+
+    /// Builds an archive from the pairs returned by `source` and writes it to `dest`. With
+    /// compression, `source` is iterated twice: once to sample values for the dictionary, and
+    /// once to compress them. It must return the same pairs both times.
+    pub fn build<F, I, V, W>(&self, source: F, dest: W) -> Result<(), Box<dyn Error>>
+    where
+        F: Fn() -> I,
+        I: IntoIterator<Item = (Key, V)>,
+        V: AsRef<[u8]> + Send,
+        W: Write,
+    {
+        self.check_settings()?;
+        let dict = self.train_dictionary(&source)?;
+        if self.format_version >= FOOTER_FORMAT_VERSION {
+            let dest = BufWriter::new(dest);
+            let mut writer = match self.format_version {
+                PARTITIONED_FORMAT_VERSION => StreamingWriter::partitioned(dest)?,
+                _ => StreamingWriter::new(dest)?,
+            };
+            let entries = self.write_values(source(), &dict, |value| writer.push_value(value))?;
+            return self.finish_streaming(entries, &dict, writer);
+        }
+        match &self.spool_dir {
+            None => {
+                let mut values = Vec::new();
+                let entries = self.write_values(source(), &dict, appender(&mut values))?;
+                self.write_archive(entries, &dict, &values[..], values.len(), dest)
+            }
+            Some(dir) => {
+                let mut spool = BufWriter::new(tempfile::tempfile_in(dir)?);
+                let entries = self.write_values(source(), &dict, appender(&mut spool))?;
+                let mut spool = spool.into_inner().map_err(|e| e.into_error())?;
+                let values_len = spool.stream_position()? as usize;
+                spool.rewind()?;
+                self.write_archive(entries, &dict, BufReader::new(spool), values_len, dest)
+            }
+        }
+    }
+
+    /// Like `build`, but takes the pairs themselves, keeping them in memory even with a
+    /// `spool_dir`.
+    pub fn build_from_pairs<V, W>(
+        &self,
+        pairs: impl IntoIterator<Item = (Key, V)>,
+        dest: W,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        V: AsRef<[u8]> + Sync,
+        W: Write,
+    {
+        let pairs = pairs.into_iter().collect::<Vec<_>>();
+        self.build(|| pairs.iter().map(|(key, value)| (*key, value)), dest)
+    }
+
+    /// Like `build`, but writes the archive to a file at `path`. It is built in a temporary file
+    /// next to `path`, which replaces `path` only once it is complete, so a failed build leaves
+    /// an existing archive there untouched.
+    pub fn build_to_path<F, I, V>(
+        &self,
+        source: F,
+        path: impl AsRef<Path>,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        F: Fn() -> I,
+        I: IntoIterator<Item = (Key, V)>,
+        V: AsRef<[u8]> + Send,
+    {
+        let path = path.as_ref();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        self.build(source, &mut file)?;
+        file.as_file().sync_all()?;
+        file.persist(path)?;
+        Ok(())
+    }
+
+    fn check_settings(&self) -> Result<(), Box<dyn Error>> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        if !(self.load_factor > 0.0 && self.load_factor <= 1.0) {
+            return Err(invalid(format!(
+                "Load factor must be in (0, 1], got {}",
+                self.load_factor
+            ))
+            .into());
+        }
+        if !SUPPORTED_BUCKET_SIZES.contains(&self.bucket_size) {
+            return Err(invalid(format!(
+                "Bucket size must be one of {:?}, got {}",
+                SUPPORTED_BUCKET_SIZES, self.bucket_size
+            ))
+            .into());
+        }
+        if self.index_partitions == 0 {
+            return Err(invalid("Index partitions must be at least 1".to_string()).into());
+        }
+        let versions = [
+            FILE_FORMAT_VERSION,
+            FOOTER_FORMAT_VERSION,
+            PARTITIONED_FORMAT_VERSION,
+        ];
+        if !versions.contains(&self.format_version) {
+            return Err(invalid(format!(
+                "Format version must be one of {:?}, got {}",
+                versions, self.format_version
+            ))
+            .into());
+        }
+        if self.index_partitions > 1 && self.format_version != PARTITIONED_FORMAT_VERSION {
+            return Err(invalid(format!(
+                "A partitioned index needs format version {}, got {}",
+                PARTITIONED_FORMAT_VERSION, self.format_version
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Returns the dictionary, or nothing for uncompressed archives. The sample is drawn with a
+    /// fixed seed, so building the same pairs twice gives the same archive.
+    fn train_dictionary<F, I, V>(&self, source: &F) -> Result<Vec<u8>, Box<dyn Error>>
+    where
+        F: Fn() -> I,
+        I: IntoIterator<Item = (Key, V)>,
+        V: AsRef<[u8]>,
+    {
+        match self.compression {
+            Compression::None => Ok(Vec::new()),
+            Compression::Zstd {
+                max_dict_size,
+                sample_count,
+            } => {
+                let rng = &mut rand::rngs::StdRng::seed_from_u64(0);
+                let values = source().into_iter().map(|(_, value)| value);
+                let samples = utils::sample(rng, sample_count, values);
+                zstd::dict::from_samples(&samples, max_dict_size).map_err(|e| {
+                    let message = format!(
+                        "Training a dictionary on {} values failed ({}), too few values can't be \
+                         compressed with a dictionary",
+                        samples.len(),
+                        e
+                    );
+                    io::Error::new(e.kind(), message).into()
+                })
+            }
+        }
+    }
+
+    /// Compresses the values if there is a dictionary and hands them to `push` in order, which
+    /// returns their addresses. Returns the index entries.
+    fn write_values<I, V>(
+        &self,
+        pairs: I,
+        dict: &[u8],
+        mut push: impl FnMut(&[u8]) -> io::Result<(u64, u64)>,
+    ) -> Result<Entries, Box<dyn Error>>
+    where
+        I: IntoIterator<Item = (Key, V)>,
+        V: AsRef<[u8]> + Send,
+    {
+        let compression_dict = (!dict.is_empty())
+            .then(|| zstd::dict::EncoderDictionary::copy(dict, COMPRESSION_LEVEL));
+        let mut pairs = pairs.into_iter();
+        let mut entries = match self.index_partitions {
+            1 => Entries::InMemory(Vec::new()),
+            partitions => {
+                let dir = self.spool_dir.clone().unwrap_or_else(std::env::temp_dir);
+                Entries::Partitioned(PartitionRuns::new(dir, partitions)?)
+            }
+        };
+        loop {
+            let (keys, values): (Vec<_>, Vec<_>) = pairs.by_ref().take(VALUE_CHUNK_SIZE).unzip();
+            if keys.is_empty() {
+                break;
+            }
+            let values: Vec<Cow<[u8]>> = match &compression_dict {
+                None => values.iter().map(|v| Cow::Borrowed(v.as_ref())).collect(),
+                Some(compression_dict) => values
+                    .into_par_iter()
+                    .map_init(
+                        || build_compressor(compression_dict),
+                        |enc, v| enc.compress(v.as_ref()).map(Cow::Owned),
+                    )
+                    .collect::<io::Result<_>>()?,
+            };
+            for (key, value) in keys.into_iter().zip(values) {
+                entries.push(key, push(&value)?)?;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn write_archive(
+        &self,
+        entries: Entries,
+        dict: &[u8],
+        values: impl io::Read,
+        values_len: usize,
+        dest: impl Write,
+    ) -> Result<(), Box<dyn Error>> {
+        match self.bucket_size {
+            1 => self.write_archive_with::<1>(entries, dict, values, values_len, dest),
+            2 => self.write_archive_with::<2>(entries, dict, values, values_len, dest),
+            4 => self.write_archive_with::<4>(entries, dict, values, values_len, dest),
+            8 => self.write_archive_with::<8>(entries, dict, values, values_len, dest),
+            16 => self.write_archive_with::<16>(entries, dict, values, values_len, dest),
+            _ => unreachable!("bucket size is checked before building"),
+        }
+    }
+
+    fn write_archive_with<const BS: usize>(
+        &self,
+        entries: Entries,
+        dict: &[u8],
+        values: impl io::Read,
+        values_len: usize,
+        dest: impl Write,
+    ) -> Result<(), Box<dyn Error>> {
+        let index = self.build_index::<BS>(entries)?;
+        let mut dest = BufWriter::new(dest);
+        write_combined_file(&*index, dict, &self.metadata, values, values_len, &mut dest)?;
+        dest.flush()?;
+        Ok(())
+    }
+
+    fn finish_streaming<W: Write>(
+        &self,
+        entries: Entries,
+        dict: &[u8],
+        writer: StreamingWriter<W>,
+    ) -> Result<(), Box<dyn Error>> {
+        match self.bucket_size {
+            1 => self.finish_streaming_with::<1, _>(entries, dict, writer),
+            2 => self.finish_streaming_with::<2, _>(entries, dict, writer),
+            4 => self.finish_streaming_with::<4, _>(entries, dict, writer),
+            8 => self.finish_streaming_with::<8, _>(entries, dict, writer),
+            16 => self.finish_streaming_with::<16, _>(entries, dict, writer),
+            _ => unreachable!("bucket size is checked before building"),
+        }
+    }
+
+    fn finish_streaming_with<const BS: usize, W: Write>(
+        &self,
+        entries: Entries,
+        dict: &[u8],
+        writer: StreamingWriter<W>,
+    ) -> Result<(), Box<dyn Error>> {
+        let index = self.build_index::<BS>(entries)?;
+        writer.finish(&*index, dict, &self.metadata)?.flush()?;
+        Ok(())
+    }
+
+    /// Builds the index, or for a partitioned one, finishes the runs its regions are built from
+    /// while it is written.
+    fn build_index<const BS: usize>(&self, entries: Entries) -> Result<Index<BS>, Box<dyn Error>> {
+        let ratio = 1.0 / self.load_factor;
+        match entries {
+            Entries::InMemory(entries) => {
+                let table = StaticCuckooTable::<BS, NUM_HASHERS, _, _>::try_par_from_vec(
+                    entries,
+                    ratio,
+                    self.index_build,
+                )
+                .map_err(|collisions| {
+                    let message = format!(
+                        "{} keys couldn't be placed in the index, try a lower load factor",
+                        collisions
+                    );
+                    io::Error::new(io::ErrorKind::InvalidInput, message)
+                })?;
+                Ok(Box::new(table))
+            }
+            Entries::Partitioned(runs) => {
+                let table = runs
+                    .into_table(ratio)?
+                    .with_parallel_build(self.index_build);
+                Ok(Box::new(table))
+            }
+        }
+    }
+}
+
+/// The index entries collected while the values are written.
+enum Entries {
+    InMemory(Vec<Entry>),
+    /// One run per region of the index, see `IkvblobBuilder::index_partitions`.
+    Partitioned(PartitionRuns<Key, (u64, u64)>),
+}
+
+impl Entries {
+    fn push(&mut self, key: Key, address: (u64, u64)) -> io::Result<()> {
+        match self {
+            Entries::InMemory(entries) => entries.push((key, address)),
+            Entries::Partitioned(runs) => runs.push(key, address)?,
+        }
+        Ok(())
+    }
+}
+
+/// Appends values to `out`, addressing them from its start.
+fn appender(mut out: impl Write) -> impl FnMut(&[u8]) -> io::Result<(u64, u64)> {
+    let mut offset = 0u64;
+    move |value| {
+        out.write_all(value)?;
+        let address = (offset, value.len() as u64);
+        offset += value.len() as u64;
+        Ok(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        fileformat_read::IkvblobView,
+        fileformat_write::{IkvblobHeader, StaticSizeSerializable, CHECKSUM_SIZE},
+        metadata::INDEX_PARTITIONS_KEY,
+    };
+
+    use super::*;
+
+    fn key(i: u32) -> Key {
+        let mut digest = [0u8; 32];
+        digest[..4].copy_from_slice(&i.to_le_bytes());
+        Multihash::<32>::wrap(1, digest)
+    }
+
+    fn small_zstd() -> Compression {
+        Compression::Zstd {
+            max_dict_size: 16 * 1024,
+            sample_count: 1000,
+        }
+    }
+
+    fn value(i: u32) -> Vec<u8> {
+        format!("{{\"id\": {}, \"name\": \"entry number {}\"}}", i, i % 97).into_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_builder() {
+        let source = || (0..5000u32).map(|i| (key(i), value(i)));
+
+        for compression in [Compression::None, small_zstd()] {
+            for bucket_size in [2, 4, 8] {
+                let mut archive = Vec::new();
+                IkvblobBuilder::new()
+                    .compression(compression.clone())
+                    .bucket_size(bucket_size)
+                    .load_factor(0.5)
+                    .metadata(BTreeMap::from([("name", "test")]))
+                    .build(source, &mut archive)
+                    .unwrap();
+
+                let view = IkvblobView::<_, _, (u64, u64)>::wrap(archive)
+                    .await
+                    .unwrap();
+                view.verify(|_, _| {}).await.unwrap();
+                assert_eq!(
+                    view.metadata().get_as::<String>("name").unwrap().as_deref(),
+                    Some("test")
+                );
+                for i in (0..5000).step_by(7) {
+                    assert_eq!(view.lookup(&key(i)).await.unwrap(), Some(value(i)));
+                }
+                assert_eq!(view.lookup(&key(5000)).await.unwrap(), None);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_build_from_pairs() {
+        // The last value of a duplicate key wins
+        let pairs = [
+            (key(1), b"a".to_vec()),
+            (key(2), b"b".to_vec()),
+            (key(1), b"c".to_vec()),
+        ];
+        let file = tempfile::NamedTempFile::new().unwrap();
+        IkvblobBuilder::new()
+            .build_from_pairs(pairs, std::fs::File::create(file.path()).unwrap())
+            .unwrap();
+        let view = IkvblobView::<_, _, (u64, u64)>::wrap(std::fs::read(file.path()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(view.lookup(&key(1)).await.unwrap(), Some(b"c".to_vec()));
+        assert_eq!(view.lookup(&key(2)).await.unwrap(), Some(b"b".to_vec()));
+
+        // Building the same pairs again gives the same archive
+        let source = || (0..5000u32).map(|i| (key(i), value(i)));
+        let builder = IkvblobBuilder::new().compression(small_zstd());
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        builder.build(source, &mut a).unwrap();
+        builder.build_to_path(source, file.path()).unwrap();
+        builder.build_from_pairs(source(), &mut b).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a, std::fs::read(file.path()).unwrap());
+
+        // A failed rebuild keeps the archive, and leaves no temporary file behind
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.ikvblob");
+        builder.build_to_path(source, &path).unwrap();
+        let failing = IkvblobBuilder::new().bucket_size(1).load_factor(1.0);
+        assert!(failing.build_to_path(source, &path).is_err());
+        assert_eq!(a, std::fs::read(&path).unwrap());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let empty = std::iter::empty::<(Key, Vec<u8>)>();
+        let mut archive = Vec::new();
+        IkvblobBuilder::new()
+            .build_from_pairs(empty, &mut archive)
+            .unwrap();
+        let view = IkvblobView::<_, _, (u64, u64)>::wrap(archive)
+            .await
+            .unwrap();
+        assert_eq!(view.lookup(&key(1)).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_spooled_values() {
+        let source = || (0..5000u32).map(|i| (key(i), value(i)));
+        let spool_dir = tempfile::tempdir().unwrap();
+        for compression in [Compression::None, small_zstd()] {
+            let builder = IkvblobBuilder::new().compression(compression);
+            let (mut in_memory, mut spooled) = (Vec::new(), Vec::new());
+            builder.build(source, &mut in_memory).unwrap();
+            builder
+                .clone()
+                .spool_dir(spool_dir.path())
+                .build(source, &mut spooled)
+                .unwrap();
+            assert_eq!(in_memory, spooled);
+        }
+        // The spool file is gone
+        assert_eq!(std::fs::read_dir(spool_dir.path()).unwrap().count(), 0);
+
+        let missing_dir = spool_dir.path().join("missing");
+        let builder = IkvblobBuilder::new().spool_dir(missing_dir);
+        assert!(builder.build(source, &mut Vec::new()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_footer_layout() {
+        let source = || (0..5000u32).map(|i| (key(i), value(i)));
+        let spool_dir = tempfile::tempdir().unwrap();
+        for compression in [Compression::None, small_zstd()] {
+            let builder = IkvblobBuilder::new()
+                .compression(compression)
+                .format_version(FOOTER_FORMAT_VERSION)
+                .metadata(BTreeMap::from([("name", "test")]));
+            // The footer layout is written in a single pass, with or without a spool file
+            let mut archive = Vec::new();
+            builder.build(source, &mut archive).unwrap();
+            assert_eq!(archive[8], FOOTER_FORMAT_VERSION as u8);
+            let mut spooled = Vec::new();
+            builder
+                .clone()
+                .spool_dir(spool_dir.path())
+                .build(source, &mut spooled)
+                .unwrap();
+            assert_eq!(archive, spooled);
+
+            let view = IkvblobView::<_, _, (u64, u64)>::wrap(archive.clone())
+                .await
+                .unwrap();
+            view.verify(|_, _| {}).await.unwrap();
+            assert_eq!(
+                view.metadata().get_as::<String>("name").unwrap().as_deref(),
+                Some("test")
+            );
+            for i in (0..5000).step_by(7) {
+                assert_eq!(view.lookup(&key(i)).await.unwrap(), Some(value(i)));
+            }
+            assert_eq!(view.lookup(&key(5000)).await.unwrap(), None);
+
+            let view = IkvblobView::<_, _, (u64, u64)>::wrap_blocking(archive.clone()).unwrap();
+            assert_eq!(view.lookup_blocking(&key(3)).unwrap(), Some(value(3)));
+
+            // A corrupt footer is rejected
+            let footer_start = archive.len() - CHECKSUM_SIZE - IkvblobHeader::SER_SIZE;
+            let mut corrupt = archive.clone();
+            corrupt[footer_start + 16] ^= 0x40;
+            let wrapped = IkvblobView::<_, Key, (u64, u64)>::wrap(corrupt).await;
+            assert!(wrapped.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partitioned_index() {
+        let source = || {
+            (0..5000u32)
+                .map(|i| (key(i), value(i)))
+                .chain([(key(3), b"new".to_vec())])
+        };
+        let spool_dir = tempfile::tempdir().unwrap();
+        for partitions in [4, 7] {
+            let mut archive = Vec::new();
+            IkvblobBuilder::new()
+                .compression(small_zstd())
+                .index_partitions(partitions)
+                .spool_dir(spool_dir.path())
+                .build(source, &mut archive)
+                .unwrap();
+            assert_eq!(archive[8], PARTITIONED_FORMAT_VERSION as u8);
+
+            let view = IkvblobView::<_, _, (u64, u64)>::wrap(archive.clone())
+                .await
+                .unwrap();
+            view.verify(|_, _| {}).await.unwrap();
+            assert_eq!(
+                view.metadata().reserved(INDEX_PARTITIONS_KEY),
+                Some(&(partitions as u64).into())
+            );
+            for i in (0..5000).step_by(7).skip(1) {
+                assert_eq!(view.lookup(&key(i)).await.unwrap(), Some(value(i)));
+            }
+            assert_eq!(view.lookup(&key(3)).await.unwrap(), Some(b"new".to_vec()));
+            assert_eq!(view.lookup(&key(5000)).await.unwrap(), None);
+            let keys = [key(10), key(5000), key(4999)];
+            let values = view.lookup_many(&keys).await.unwrap();
+            assert_eq!(values, vec![Some(value(10)), None, Some(value(4999))]);
+
+            let view = IkvblobView::<_, _, (u64, u64)>::wrap_blocking(archive).unwrap();
+            assert_eq!(view.lookup_blocking(&key(11)).unwrap(), Some(value(11)));
+        }
+
+        // A single partition is the plain index
+        let (mut plain, mut single) = (Vec::new(), Vec::new());
+        IkvblobBuilder::new().build(source, &mut plain).unwrap();
+        IkvblobBuilder::new()
+            .index_partitions(1)
+            .build(source, &mut single)
+            .unwrap();
+        assert_eq!(plain, single);
+    }
+
+    #[tokio::test]
+    async fn test_segmented_index() {
+        // Enough keys for two regions that are large enough to be segmented
+        let source = || (0..30_000u32).map(|i| (key(i), value(i)));
+        let build = |builder: IkvblobBuilder| {
+            let mut archive = Vec::new();
+            builder.build(source, &mut archive).unwrap();
+            archive
+        };
+        let check = |archive: Vec<u8>| async move {
+            let view = IkvblobView::<_, _, (u64, u64)>::wrap(archive)
+                .await
+                .unwrap();
+            view.verify(|_, _| {}).await.unwrap();
+            for i in (0..30_000).step_by(7) {
+                assert_eq!(view.lookup(&key(i)).await.unwrap(), Some(value(i)));
+            }
+            assert_eq!(view.lookup(&key(30_000)).await.unwrap(), None);
+        };
+
+        // The index differs from the deterministic one, but is still reproducible
+        let segmented = IkvblobBuilder::new().index_build(ParallelBuild::Segmented);
+        let archive = build(segmented.clone());
+        let deterministic = build(IkvblobBuilder::new());
+        assert_eq!(archive.len(), deterministic.len());
+        assert_ne!(archive, deterministic);
+        assert_eq!(archive, build(segmented.clone()));
+        check(archive).await;
+
+        let partitioned = build(segmented.clone().index_partitions(2));
+        assert_ne!(
+            partitioned,
+            build(IkvblobBuilder::new().index_partitions(2))
+        );
+        check(partitioned).await;
+    }
+
+    #[test]
+    fn test_builder_errors() {
+        let pairs = || (0..100).map(|i| (key(i), value(i)));
+        for builder in [
+            IkvblobBuilder::new().load_factor(1.5),
+            IkvblobBuilder::new().load_factor(0.0),
+            IkvblobBuilder::new().bucket_size(3),
+            IkvblobBuilder::new().format_version(4),
+            IkvblobBuilder::new().index_partitions(0),
+            IkvblobBuilder::new().index_partitions(4).format_version(2),
+            // Each region is too small for its keys
+            IkvblobBuilder::new().index_partitions(20).load_factor(1.0),
+            // The keys can't all be placed
+            IkvblobBuilder::new().bucket_size(1).load_factor(1.0),
+        ] {
+            assert!(builder.build(pairs, &mut Vec::new()).is_err());
+        }
+
+        // Too few values to train a dictionary
+        let builder = IkvblobBuilder::new().compression(small_zstd());
+        let pairs = || [(key(1), b"a".to_vec())];
+        assert!(builder.build(pairs, &mut Vec::new()).is_err());
+    }
+}
@@ -34,7 +34,18 @@ where
     Ok(dict_buffer)
 }
 
-const COMPRESSION_LEVEL: i32 = 5;
+pub(crate) const COMPRESSION_LEVEL: i32 = 5;
+
+/// Builds the compressor for values. It always writes the uncompressed size into the frame
+/// header, which the reader uses to decompress a value in one go.
+pub(crate) fn build_compressor<'a>(
+    compression_dict: &'a zstd::dict::EncoderDictionary<'_>,
+) -> zstd::bulk::Compressor<'a> {
+    let mut enc = zstd::bulk::Compressor::with_prepared_dictionary(compression_dict).unwrap();
+    enc.set_parameter(zstd::zstd_safe::CParameter::ContentSizeFlag(true))
+        .unwrap();
+    enc
+}
 
 pub fn construct_simple2<V, FNV>(
     data: Vec<V>,
@@ -50,7 +61,7 @@ where
     let sample = &data.choose_multiple(rng, sample_count).collect::<Vec<_>>();
     let dict_buffer = zstd::dict::from_samples(sample, max_dict_size_in_bytes)?;
     let compression_dict = zstd::dict::EncoderDictionary::copy(&dict_buffer, COMPRESSION_LEVEL);
-    let build_enc = || zstd::bulk::Compressor::with_prepared_dictionary(&compression_dict).unwrap();
+    let build_enc = || build_compressor(&compression_dict);
     let mut enc = build_enc();
 
     data.into_iter()
@@ -73,7 +84,7 @@ where
     let sample = &data.choose_multiple(rng, sample_count).collect::<Vec<_>>();
     let dict_buffer = zstd::dict::from_samples(sample, max_dict_size_in_bytes)?;
     let compression_dict = zstd::dict::EncoderDictionary::copy(&dict_buffer, COMPRESSION_LEVEL);
-    let build_enc = || zstd::bulk::Compressor::with_prepared_dictionary(&compression_dict).unwrap();
+    let build_enc = || build_compressor(&compression_dict);
 
     // let mut encoded = pariter::scope(|scope| data
     //     .into_iter()
@@ -141,7 +152,7 @@ where
     let stream = get_iterator();
     let compression_dict = zstd::dict::EncoderDictionary::copy(&dict_buffer, COMPRESSION_LEVEL);
 
-    let build_enc = || zstd::bulk::Compressor::with_prepared_dictionary(&compression_dict).unwrap();
+    let build_enc = || build_compressor(&compression_dict);
 
     let writer_fn = |v: Vec<u8>| {
         value_cb(v);
@@ -1,10 +1,111 @@
 // use umash::Params;
-use std::mem;
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs::File,
+    io::{self, BufReader, BufWriter, Seek},
+    marker::PhantomData,
+    mem,
+    ops::Range,
+    path::Path,
+};
+
+use rayon::prelude::*;
 
 use crate::{
     fileformat_write::StaticSizeSerializable, parametrized_hasher::SipHasherFactory as SHF,
 };
 
+/// Number of evictions after which an insertion gives up.
+const MAX_EVICTIONS: usize = 1000;
+
+/// Number of evictions within a segment after which `ParallelBuild::Segmented` defers an entry
+/// to be placed afterwards. Most entries have only one candidate bucket in their segment, so
+/// longer chains rarely help.
+const MAX_SEGMENT_EVICTIONS: usize = 16;
+
+/// Number of keys hashed together, in parallel, before they are placed.
+const HASHING_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Bounds on the segments of `ParallelBuild::Segmented`. They depend only on the size of the
+/// table, not on the number of threads, so that the table does neither.
+const MIN_SEGMENT_BUCKETS: usize = 1024;
+const MAX_SEGMENTS: usize = 1024;
+
+/// Parameter of the hasher that assigns keys to index partitions. It is distinct from the
+/// parameters of the bucket hashers, which count up from 0.
+pub const PARTITION_HASHER_PARAM: u64 = u64::MAX;
+
+/// The partition of `key` when the index is split into `partitions` regions.
+pub fn partition_of<K: StaticSizeSerializable>(key: &K, partitions: usize) -> usize {
+    if partitions <= 1 {
+        return 0;
+    }
+    (SHF::new(PARTITION_HASHER_PARAM).hash(key) % partitions as u64) as usize
+}
+
+/// Range of buckets that `key` can be placed in, when the `num_buckets` buckets of an index are
+/// split into `partitions` regions of equal size. The bucket hashers pick buckets within it.
+pub fn region_of<K: StaticSizeSerializable>(
+    key: &K,
+    num_buckets: usize,
+    partitions: usize,
+) -> Range<usize> {
+    if partitions <= 1 {
+        return 0..num_buckets;
+    }
+    let region_len = num_buckets / partitions;
+    let partition = partition_of(key, partitions);
+    partition * region_len..(partition + 1) * region_len
+}
+
+/// Number of buckets for `len` entries in buckets of `bs` entries, with `ratio` slots per entry.
+fn num_buckets_for(len: usize, ratio: f32, bs: usize) -> usize {
+    // At least one bucket, so that lookups in an empty table have somewhere to look
+    ((ratio * (len as f32) / bs as f32).ceil() as usize).max(1)
+}
+
+pub type Bucket<const BS: usize, K, V> = [Option<(K, V)>; BS];
+
+/// How `StaticCuckooTable::try_par_from_vec` uses several threads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParallelBuild {
+    /// Hashes the keys in parallel, but places them one by one. The table is byte-identical to
+    /// the one `try_from_iter` builds from the same entries.
+    #[default]
+    Deterministic,
+    /// Splits the table into segments, and places the keys whose first bucket is in a segment
+    /// in parallel with the other segments. Keys that don't fit into their segment are placed one
+    /// by one afterwards. The table differs from the one `try_from_iter` builds, but is the same
+    /// for the same entries on any machine. Needs about twice the memory of the entries.
+    Segmented,
+}
+
+/// The candidate buckets of `key` in a table of `num_buckets` buckets, one per hasher.
+fn bucket_indices<K: StaticSizeSerializable, const HS: usize>(
+    hashers: &[SHF; HS],
+    key: &K,
+    num_buckets: usize,
+) -> [usize; HS] {
+    hashers
+        .each_ref()
+        .map(|hasher| (hasher.hash(key) % num_buckets as u64) as usize)
+}
+
+/// An index that can be written out bucket by bucket.
+pub trait CuckooIndex<const BS: usize, const HS: usize, K, V> {
+    fn num_buckets(&self) -> usize;
+
+    /// Number of regions the buckets are split into, see `region_of`.
+    fn partitions(&self) -> usize { 1 }
+
+    /// Passes the buckets to `f` in order.
+    fn try_for_each_bucket(
+        &self,
+        f: &mut dyn FnMut(&Bucket<BS, K, V>) -> io::Result<()>,
+    ) -> Result<(), Box<dyn Error>>;
+}
+
 fn view_based_lookup<'a, K, V, const BS: usize, const HS: usize>(
     table: &'a [[Option<(K, V)>; BS]],
     key: &K,
@@ -48,9 +149,44 @@ where
     where
         IT: ExactSizeIterator<Item = (K, V)>,
     {
-        let arch_len = elems.len() as u64;
-        let outer_size = (ratio * (arch_len as f32) / BS as f32).ceil() as usize;
+        match Self::try_from_iter(elems, ratio) {
+            Ok(table) => table,
+            Err(collisions) => panic!("Collisions: {}", collisions),
+        }
+    }
+
+    /// Like `from_iter`, but returns the number of entries that couldn't be placed instead of
+    /// panicking, e.g. because `ratio` is too small.
+    pub fn try_from_iter<IT>(elems: IT, ratio: f32) -> Result<Self, usize>
+    where
+        IT: ExactSizeIterator<Item = (K, V)>,
+    {
+        let outer_size = num_buckets_for(elems.len(), ratio, BS);
+        Self::try_with_buckets(elems, outer_size)
+    }
 
+    /// Like `try_from_iter`, but with `outer_size` buckets.
+    pub fn try_with_buckets<IT>(elems: IT, outer_size: usize) -> Result<Self, usize>
+    where
+        IT: Iterator<Item = (K, V)>,
+    {
+        let mut table = Self::empty(outer_size);
+        let mut collisions = 0;
+        for (key, val) in elems {
+            let buckets = table.buckets_of(&key);
+            if !table.insert(key, val, buckets) {
+                collisions += 1;
+            }
+        }
+
+        if collisions > 0 {
+            return Err(collisions);
+        }
+
+        Ok(table)
+    }
+
+    fn empty(outer_size: usize) -> Self {
         // let mut table: Vec<[Option<(K, V)>; BS]> = vec![[None; BS]; outer_size];
         // This uglier initialization is necessary because V isn't Copy so Option<(K, V)> isn't either
         let mut table = Vec::<[Option<(K, V)>; BS]>::new();
@@ -65,71 +201,366 @@ where
             })
         };
 
-        let mut collisions = 0;
-
-        for (key, val) in elems {
-            let mut key = key;
-            let mut val = val;
-            let mut i = 0;
-
-            let mut replace_with_which_outer = 0;
-            let mut replace_with_which_inner = 0;
-            // let mut hash_start_pos = 2;
-
-            'outer: loop {
-                let mut htorep = 0usize;
-                for j in 0..hashers.len() {
-                    let h = (hashers[j].hash(&key) % (table.len() as u64)) as usize;
+        Self { table, hashers }
+    }
 
-                    if j == replace_with_which_outer {
-                        htorep = h;
-                    }
+    /// The candidate buckets of `key`, one per hasher.
+    fn buckets_of(&self, key: &K) -> [usize; HS] {
+        bucket_indices(&self.hashers, key, self.table.len())
+    }
 
-                    for k in 0..BS {
-                        match table[h][k] {
-                            None => {
-                                table[h][k] = Some((key, val));
-                                break 'outer;
-                            }
-                            Some((entry_key, _)) if entry_key == key => {
-                                // Handle duplicate values by inserting the latest one
-                                table[h][k] = Some((key, val));
-                                break 'outer;
-                            }
-                            _ => {}
+    /// Inserts an entry whose candidate buckets are `buckets`, evicting other entries if they are
+    /// full. Returns false if it gave up, in which case the entry that was being moved at the
+    /// time is dropped.
+    fn insert(&mut self, key: K, val: V, buckets: [usize; HS]) -> bool {
+        let table = &mut self.table;
+        let mut key = key;
+        let mut val = val;
+        let mut buckets = buckets;
+        let mut i = 0;
+
+        let mut replace_with_which_outer = 0;
+        let mut replace_with_which_inner = 0;
+        // let mut hash_start_pos = 2;
+
+        loop {
+            for &h in buckets.iter() {
+                for k in 0..BS {
+                    match table[h][k] {
+                        None => {
+                            table[h][k] = Some((key, val));
+                            return true;
+                        }
+                        Some((entry_key, _)) if entry_key == key => {
+                            // Handle duplicate values by inserting the latest one
+                            table[h][k] = Some((key, val));
+                            return true;
                         }
-                        // if table[h][k].is_none() {
-                        //     table[h][k] = Some((key, val));
-                        //     break 'outer;
-                        // }
+                        _ => {}
                     }
                 }
-                let postorep = replace_with_which_inner;
+            }
+            let htorep = buckets[replace_with_which_outer];
+            let postorep = replace_with_which_inner;
+
+            replace_with_which_outer = (replace_with_which_outer + 1) % HS;
+            replace_with_which_inner = (replace_with_which_outer + 1) % BS;
+
+            let current = Some((key, val));
+            let old = mem::replace(&mut table[htorep][postorep], current).unwrap();
 
-                replace_with_which_outer = (replace_with_which_outer + 1) % hashers.len();
-                replace_with_which_inner = (replace_with_which_outer + 1) % BS;
-                // let old = table[htorep][postorep].unwrap();
-                // table[htorep][postorep] = Some((key, val));
+            val = old.1;
+            key = old.0;
+            i += 1;
+            if i > MAX_EVICTIONS {
+                // Probably stuck in a loop
+                return false;
+            }
+            buckets = bucket_indices(&self.hashers, &key, table.len());
+        }
+    }
+}
+
+impl<const BS: usize, const HS: usize, K, V> StaticCuckooTable<BS, HS, K, V>
+where
+    K: Eq + Copy + StaticSizeSerializable + Send + Sync,
+    V: Send + Sync,
+{
+    /// Like `try_from_iter`, but uses several threads, see `ParallelBuild`.
+    pub fn try_par_from_vec(
+        elems: Vec<(K, V)>,
+        ratio: f32,
+        build: ParallelBuild,
+    ) -> Result<Self, usize> {
+        let outer_size = num_buckets_for(elems.len(), ratio, BS);
+        Self::try_par_with_buckets(elems, outer_size, build)
+    }
 
-                let current = Some((key, val));
-                let old = mem::replace(&mut table[htorep][postorep], current).unwrap();
+    /// Like `try_par_from_vec`, but with `outer_size` buckets.
+    pub fn try_par_with_buckets(
+        elems: Vec<(K, V)>,
+        outer_size: usize,
+        build: ParallelBuild,
+    ) -> Result<Self, usize> {
+        let mut table = Self::empty(outer_size);
+        let deferred = match build {
+            ParallelBuild::Deterministic => elems,
+            ParallelBuild::Segmented => table.place_in_segments(elems),
+        };
 
-                val = old.1;
-                key = old.0;
-                i += 1;
-                if i > 1000 {
-                    // Probably stuck in a loop
+        // Only the hashing is parallel here, the entries are placed in order like in
+        // `try_with_buckets`
+        let mut collisions = 0;
+        let mut deferred = deferred.into_iter();
+        loop {
+            let chunk = deferred
+                .by_ref()
+                .take(HASHING_CHUNK_SIZE)
+                .collect::<Vec<_>>();
+            if chunk.is_empty() {
+                break;
+            }
+            let buckets = chunk
+                .par_iter()
+                .map(|(key, _)| table.buckets_of(key))
+                .collect::<Vec<_>>();
+            for ((key, val), buckets) in chunk.into_iter().zip(buckets) {
+                if !table.insert(key, val, buckets) {
                     collisions += 1;
-                    break 'outer;
                 }
             }
         }
 
         if collisions > 0 {
-            panic!("Collisions: {}", collisions);
+            return Err(collisions);
         }
 
-        Self { table, hashers }
+        Ok(table)
+    }
+
+    /// Places the entries whose first candidate bucket is in the same segment of the table in
+    /// parallel, evicting entries only within the segment. Returns the entries that didn't fit,
+    /// in order.
+    fn place_in_segments(&mut self, elems: Vec<(K, V)>) -> Vec<(K, V)> {
+        let num_buckets = self.table.len();
+        if num_buckets < 2 * MIN_SEGMENT_BUCKETS {
+            return elems;
+        }
+        let segment_len =
+            num_buckets.div_ceil((num_buckets / MIN_SEGMENT_BUCKETS).min(MAX_SEGMENTS));
+        let segments = num_buckets.div_ceil(segment_len);
+
+        let buckets = elems
+            .par_iter()
+            .map(|(key, _)| self.buckets_of(key))
+            .collect::<Vec<_>>();
+        let mut per_segment = (0..segments).map(|_| Vec::new()).collect::<Vec<_>>();
+        for (entry, buckets) in elems.into_iter().zip(buckets) {
+            per_segment[buckets[0] / segment_len].push((entry, buckets));
+        }
+
+        let hashers = &self.hashers;
+        self.table
+            .par_chunks_mut(segment_len)
+            .zip(per_segment)
+            .enumerate()
+            .map(|(i, (segment, entries))| {
+                place_in_segment(segment, i * segment_len, num_buckets, hashers, entries)
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+}
+
+/// Places `entries`, with their candidate buckets, in `segment`, which holds the buckets from
+/// `offset` on. Only candidate buckets in the segment are used, and entries are only evicted
+/// within it. Returns the entries it couldn't place, in order.
+fn place_in_segment<const BS: usize, const HS: usize, K, V>(
+    segment: &mut [Bucket<BS, K, V>],
+    offset: usize,
+    num_buckets: usize,
+    hashers: &[SHF; HS],
+    entries: Vec<((K, V), [usize; HS])>,
+) -> Vec<(K, V)>
+where
+    K: Eq + Copy + StaticSizeSerializable,
+{
+    let range = offset..offset + segment.len();
+    let mut deferred = Vec::new();
+    // Later entries with the same key as a deferred one must be deferred too, so that the last
+    // value still wins. Entries with the same candidate buckets are, which is a superset.
+    let mut deferred_buckets = HashSet::new();
+
+    for ((key, val), buckets) in entries {
+        if deferred_buckets.contains(&buckets) {
+            deferred.push((key, val));
+            continue;
+        }
+        let (mut key, mut val, mut buckets) = (key, val, buckets);
+        let mut evictions = 0;
+        'placed: loop {
+            let local = buckets
+                .iter()
+                .filter(|h| range.contains(h))
+                .map(|h| h - offset);
+            for h in local.clone() {
+                for slot in segment[h].iter_mut() {
+                    match slot {
+                        None => {
+                            *slot = Some((key, val));
+                            break 'placed;
+                        }
+                        Some((entry_key, _)) if *entry_key == key => {
+                            *slot = Some((key, val));
+                            break 'placed;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            if evictions == MAX_SEGMENT_EVICTIONS {
+                deferred_buckets.insert(buckets);
+                deferred.push((key, val));
+                break;
+            }
+            // The first candidate bucket of every entry in the segment is in it
+            let h = local.clone().nth(evictions % local.count()).unwrap();
+            let current = Some((key, val));
+            let old = mem::replace(&mut segment[h][evictions % BS], current).unwrap();
+            (key, val) = old;
+            buckets = bucket_indices(hashers, &key, num_buckets);
+            evictions += 1;
+        }
+    }
+    deferred
+}
+
+impl<const BS: usize, const HS: usize, K, V> CuckooIndex<BS, HS, K, V>
+    for StaticCuckooTable<BS, HS, K, V>
+where
+    K: Eq + Copy + StaticSizeSerializable,
+{
+    fn num_buckets(&self) -> usize { self.table.len() }
+
+    fn try_for_each_bucket(
+        &self,
+        f: &mut dyn FnMut(&Bucket<BS, K, V>) -> io::Result<()>,
+    ) -> Result<(), Box<dyn Error>> {
+        for bucket in self.table.iter() {
+            f(bucket)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects index entries in on-disk runs, one per partition, so that an index that doesn't fit
+/// in memory can be built one region at a time, see `PartitionedCuckooTable`.
+pub struct PartitionRuns<K, V> {
+    runs: Vec<BufWriter<File>>,
+    len: usize,
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<K, V> PartitionRuns<K, V>
+where
+    K: Eq + Copy + StaticSizeSerializable,
+    Option<(K, V)>: StaticSizeSerializable,
+{
+    /// Creates `partitions` temporary files in `dir`. They are deleted when the runs are dropped.
+    pub fn new(dir: impl AsRef<Path>, partitions: usize) -> io::Result<Self> {
+        let runs = (0..partitions.max(1))
+            .map(|_| Ok(BufWriter::new(tempfile::tempfile_in(dir.as_ref())?)))
+            .collect::<io::Result<_>>()?;
+        Ok(PartitionRuns {
+            runs,
+            len: 0,
+            phantom: PhantomData,
+        })
+    }
+
+    pub fn push(&mut self, key: K, val: V) -> io::Result<()> {
+        let partition = partition_of(&key, self.runs.len());
+        Some((key, val)).write(&mut self.runs[partition])?;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize { self.len }
+
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// Finishes the runs. The regions are sized for `ratio` slots per entry, like
+    /// `StaticCuckooTable::from_iter`.
+    pub fn into_table<const BS: usize, const HS: usize>(
+        self,
+        ratio: f32,
+    ) -> io::Result<PartitionedCuckooTable<BS, HS, K, V>> {
+        let partitions = self.runs.len();
+        let region_len = num_buckets_for(self.len, ratio, BS).div_ceil(partitions);
+        let runs = self
+            .runs
+            .into_iter()
+            .map(|run| run.into_inner().map_err(|e| e.into_error()))
+            .collect::<io::Result<_>>()?;
+        Ok(PartitionedCuckooTable {
+            runs,
+            region_len,
+            build: ParallelBuild::default(),
+            phantom: PhantomData,
+        })
+    }
+}
+
+/// An index whose buckets are split into regions, see `region_of`. Each region is built from its
+/// run when it is written, so only one region has to fit in memory at a time.
+pub struct PartitionedCuckooTable<const BS: usize, const HS: usize, K, V> {
+    runs: Vec<File>,
+    region_len: usize,
+    build: ParallelBuild,
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<const BS: usize, const HS: usize, K, V> PartitionedCuckooTable<BS, HS, K, V>
+where
+    K: Eq + Copy + StaticSizeSerializable + Send + Sync,
+    V: Send + Sync,
+    Option<(K, V)>: StaticSizeSerializable,
+{
+    /// Sets how each region is built, see `ParallelBuild`.
+    pub fn with_parallel_build(mut self, build: ParallelBuild) -> Self {
+        self.build = build;
+        self
+    }
+
+    /// Builds the region of the `partition`th run. Fails with the number of entries that
+    /// couldn't be placed, like `StaticCuckooTable::try_from_iter`.
+    pub fn build_region(
+        &self,
+        partition: usize,
+    ) -> Result<StaticCuckooTable<BS, HS, K, V>, Box<dyn Error>> {
+        let mut run = &self.runs[partition];
+        let len = run.seek(io::SeekFrom::End(0))? as usize / Option::<(K, V)>::SER_SIZE;
+        run.rewind()?;
+        let mut reader = BufReader::new(run);
+        let mut entries = Vec::with_capacity(len);
+        for _ in 0..len {
+            if let Some(entry) = Option::<(K, V)>::read(&mut reader)? {
+                entries.push(entry);
+            }
+        }
+        StaticCuckooTable::try_par_with_buckets(entries, self.region_len, self.build).map_err(
+            |collisions| {
+                let message = format!(
+                    "{} keys couldn't be placed in index region {}, try a lower load factor",
+                    collisions, partition
+                );
+                io::Error::new(io::ErrorKind::InvalidInput, message).into()
+            },
+        )
+    }
+}
+
+impl<const BS: usize, const HS: usize, K, V> CuckooIndex<BS, HS, K, V>
+    for PartitionedCuckooTable<BS, HS, K, V>
+where
+    K: Eq + Copy + StaticSizeSerializable + Send + Sync,
+    V: Send + Sync,
+    Option<(K, V)>: StaticSizeSerializable,
+{
+    fn num_buckets(&self) -> usize { self.region_len * self.runs.len() }
+
+    fn partitions(&self) -> usize { self.runs.len() }
+
+    fn try_for_each_bucket(
+        &self,
+        f: &mut dyn FnMut(&Bucket<BS, K, V>) -> io::Result<()>,
+    ) -> Result<(), Box<dyn Error>> {
+        for partition in 0..self.runs.len() {
+            self.build_region(partition)?.try_for_each_bucket(f)?;
+        }
+        Ok(())
     }
 }
 
@@ -252,4 +683,81 @@ mod tests {
             assert_eq!(table.lookup(&k), Some(&v));
         }
     }
+
+    #[test]
+    fn test_parallel_build() {
+        type Table = StaticCuckooTable<4, 2, u64, u64>;
+        // Every 10th key appears twice, and its second value wins
+        let inputs = (0..50_000u64)
+            .map(|x| (x, x * 2))
+            .chain((0..50_000).step_by(10).map(|x| (x, x * 3)))
+            .collect::<Vec<_>>();
+        let expected = |x: u64| if x.is_multiple_of(10) { x * 3 } else { x * 2 };
+
+        let sequential = Table::from_iter(inputs.clone().into_iter(), 1.1);
+        let deterministic =
+            Table::try_par_from_vec(inputs.clone(), 1.1, ParallelBuild::Deterministic).unwrap();
+        assert!(sequential.table == deterministic.table);
+
+        let segmented =
+            Table::try_par_from_vec(inputs.clone(), 1.1, ParallelBuild::Segmented).unwrap();
+        assert_eq!(segmented.table.len(), sequential.table.len());
+        for x in 0..50_000 {
+            assert_eq!(segmented.lookup(&x), Some(&expected(x)));
+        }
+        assert_eq!(segmented.lookup(&50_000), None);
+        let again = Table::try_par_from_vec(inputs.clone(), 1.1, ParallelBuild::Segmented).unwrap();
+        assert!(segmented.table == again.table);
+
+        // 100 buckets of 4 can't hold 500 keys
+        for build in [ParallelBuild::Deterministic, ParallelBuild::Segmented] {
+            assert!(Table::try_par_with_buckets(inputs[..500].to_vec(), 100, build).is_err());
+        }
+    }
+
+    #[test]
+    fn test_partitioned_table() {
+        use crate::{
+            fileformat_write::{write_combined_file, StreamingWriter},
+            multihash::Multihash,
+        };
+
+        let key = |i: u64| Multihash::<32>::wrap(1, i.to_le_bytes().repeat(4).try_into().unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        let mut runs = PartitionRuns::new(dir.path(), 5).unwrap();
+        for i in 0..2000 {
+            runs.push(key(i), (i, 1)).unwrap();
+        }
+        // The last value of a duplicate key wins, like in `from_iter`
+        runs.push(key(7), (7, 2)).unwrap();
+        assert_eq!(runs.len(), 2001);
+        let table = runs.into_table::<4, 2>(1.25).unwrap();
+        assert_eq!(table.partitions(), 5);
+        assert_eq!(table.num_buckets() % 5, 0);
+        // Only archives of format version 3 can hold it
+        assert!(write_combined_file(&table, &[], &(), &[][..], 0, Vec::new()).is_err());
+        let writer = StreamingWriter::new(Vec::new()).unwrap();
+        assert!(writer.finish(&table, &[], &()).is_err());
+
+        let mut buckets = Vec::new();
+        table
+            .try_for_each_bucket(&mut |bucket| {
+                buckets.push(*bucket);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(buckets.len(), table.num_buckets());
+        let hashers = [SHF::new(0), SHF::new(1)];
+        let lookup = |k| {
+            let region = region_of(&k, buckets.len(), 5);
+            view_based_lookup(&buckets[region], &k, &hashers).copied()
+        };
+        for i in 0..2000 {
+            let size = if i == 7 { 2 } else { 1 };
+            assert_eq!(lookup(key(i)), Some((i, size)));
+        }
+        assert_eq!(lookup(key(2000)), None);
+
+        assert_eq!(region_of(&key(1), 10, 1), 0..10);
+    }
 }
@@ -0,0 +1,540 @@
+//! Persistent block cache for remote archives
+//!
+//! `DiskCachedMemory` keeps the blocks it fetches in a local directory, so that a process that
+//! reopens an archive it has already read doesn't fetch the same bytes again. The directory can
+//! be shared by several processes and by several archives: every archive gets a subdirectory
+//! named after its identity, blocks are written to a temporary file and then renamed into
+//! place, and a reader that loses a race against an eviction simply fetches the block again.
+//!
+//! All file system work of a `DiskCachedMemory` happens on a thread of its own, so that reads
+//! don't block the executor they are polled on.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::SystemTime,
+};
+
+use futures::channel::oneshot;
+
+use crate::memory_view::{
+    consecutive_runs, extend_from_block, read_exact, read_exact_many, CacheStats, Memory,
+    MemoryError,
+};
+
+const BLOCK_EXTENSION: &str = "blk";
+const TMP_PREFIX: &str = ".tmp-";
+const LEN_FILE: &str = "len";
+
+/// Settings of a `DiskCachedMemory`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskCacheOptions {
+    /// Size of the aligned blocks in which reads are fetched and stored.
+    pub block_size: usize,
+    /// Total size of the cache directory, over all archives, above which the least recently
+    /// used blocks are deleted until it is at 90% of this. The limit is approximate when several
+    /// processes share the directory, as every process only notices the others' writes when it
+    /// scans it.
+    pub max_size: u64,
+}
+
+impl Default for DiskCacheOptions {
+    fn default() -> Self {
+        DiskCacheOptions {
+            block_size: 64 * 1024,
+            max_size: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A thread running the file system work of a `DiskCachedMemory`. It stops when this is dropped.
+struct IoThread {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl IoThread {
+    fn spawn() -> io::Result<Self> {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        thread::Builder::new()
+            .name("ikvblob-disk-cache".to_string())
+            .spawn(move || receiver.into_iter().for_each(|job| job()))?;
+        Ok(IoThread { jobs })
+    }
+
+    async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T, MemoryError> {
+        let (sender, receiver) = oneshot::channel();
+        let job = Box::new(move || {
+            let _ = sender.send(f());
+        });
+        let stopped = || MemoryError::Backend {
+            message: "Disk cache thread stopped".to_string(),
+            transient: false,
+        };
+        self.jobs.send(job).map_err(|_| stopped())?;
+        receiver.await.map_err(|_| stopped())
+    }
+}
+
+/// The cache directory of one archive, and the size bookkeeping of the whole cache.
+struct CacheDir {
+    root: PathBuf,
+    dir: PathBuf,
+    options: DiskCacheOptions,
+    len: usize,
+    // Size of the cache directory as of the last scan, plus what was written since
+    approx_size: AtomicU64,
+    tmp_counter: AtomicU64,
+    stats: Mutex<CacheStats>,
+}
+
+impl CacheDir {
+    fn block_path(&self, block: usize) -> PathBuf {
+        self.dir.join(format!("{}.{}", block, BLOCK_EXTENSION))
+    }
+
+    fn block_range(&self, block: usize) -> Range<usize> {
+        let start = block * self.options.block_size;
+        start..self.len.min(start + self.options.block_size)
+    }
+
+    /// The bytes of the consecutive blocks `blocks`.
+    fn byte_range(&self, blocks: &Range<usize>) -> Range<usize> {
+        self.block_range(blocks.start).start..self.block_range(blocks.end - 1).end
+    }
+
+    /// Reads a block from the cache directory. Blocks of the wrong size, e.g. left over by a
+    /// crash on a file system without atomic renames, count as missing.
+    fn read_cached_block(&self, block: usize) -> Option<Vec<u8>> {
+        let path = self.block_path(block);
+        let data = fs::read(&path).ok()?;
+        if data.len() != self.block_range(block).len() {
+            return None;
+        }
+        // The modification time orders blocks for eviction
+        if let Ok(file) = fs::File::options().write(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some(data)
+    }
+
+    fn write_atomically(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let tmp_path = self.dir.join(format!(
+            "{}{}-{}",
+            TMP_PREFIX,
+            std::process::id(),
+            self.tmp_counter.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp_path, data)
+            .and_then(|()| fs::rename(&tmp_path, path))
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&tmp_path);
+            })
+    }
+
+    fn store_block(&self, block: usize, data: &[u8]) {
+        if self.write_atomically(&self.block_path(block), data).is_err() {
+            return;
+        }
+        let size = self.approx_size.fetch_add(data.len() as u64, Ordering::Relaxed);
+        if size + data.len() as u64 > self.options.max_size {
+            self.evict();
+        }
+    }
+
+    /// Scans the whole cache directory, and if it is over its size limit, deletes the least
+    /// recently used blocks until it is at 90% of the limit. Leaving that headroom means the
+    /// next scan is only due after a tenth of the limit has been written, not on every write.
+    fn evict(&self) {
+        let mut files = Vec::new();
+        for dir in fs::read_dir(&self.root).into_iter().flatten().flatten() {
+            for file in fs::read_dir(dir.path()).into_iter().flatten().flatten() {
+                let path = file.path();
+                let is_block = path.extension().is_some_and(|e| e == BLOCK_EXTENSION);
+                let is_tmp = file.file_name().to_string_lossy().starts_with(TMP_PREFIX);
+                if let (true, Ok(metadata)) = (is_block || is_tmp, file.metadata()) {
+                    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                    files.push((modified, metadata.len(), path));
+                }
+            }
+        }
+
+        let mut size = files.iter().map(|(_, len, _)| len).sum::<u64>();
+        let target = match size > self.options.max_size {
+            true => self.options.max_size - self.options.max_size / 10,
+            false => size,
+        };
+        files.sort_unstable();
+        let mut evicted = 0;
+        for (_, len, path) in files {
+            if size <= target {
+                break;
+            }
+            // Another process may have deleted the file first, which is just as good
+            let _ = fs::remove_file(path);
+            size -= len;
+            evicted += 1;
+        }
+        self.approx_size.store(size, Ordering::Relaxed);
+        self.stats.lock().unwrap().evictions += evicted;
+    }
+}
+
+/// Caches the reads of another memory in a local directory, see the module documentation.
+///
+/// `read_slices` fetches the missing blocks of all its ranges with one `read_slices` of the inner
+/// memory. Blocks are not deduplicated between concurrent reads of the same process, wrap this
+/// in a `CachedMemory` for that. Failing to write to the cache directory doesn't fail reads.
+pub struct DiskCachedMemory<M> {
+    inner: M,
+    cache: Arc<CacheDir>,
+    io: IoThread,
+}
+
+impl<M: Memory> DiskCachedMemory<M> {
+    /// Opens the cache of the archive in `inner`, identifying the archive by its size and its
+    /// CRC32 trailer. This costs a read of the trailer every time.
+    pub async fn open(
+        inner: M,
+        root: impl Into<PathBuf>,
+        options: DiskCacheOptions,
+    ) -> Result<Self, MemoryError> {
+        let len = inner.len().await?;
+        let trailer = read_exact(&inner, len.saturating_sub(4)..len).await?;
+        let identity = format!("{}-{}", len, hex::encode(trailer));
+        let io = IoThread::spawn()?;
+        Self::open_with_len(inner, io, root.into(), &identity, len, options).await
+    }
+
+    /// Opens the cache of the archive in `inner` under a caller-provided identity, e.g. the
+    /// ETag of a remote object. The identity must change whenever the archive does. When the
+    /// archive has been opened before, this doesn't read from `inner` at all.
+    pub async fn open_with_identity(
+        inner: M,
+        root: impl Into<PathBuf>,
+        identity: &str,
+        options: DiskCacheOptions,
+    ) -> Result<Self, MemoryError> {
+        let root = root.into();
+        let len_path = root.join(hex::encode(identity)).join(LEN_FILE);
+        let io = IoThread::spawn()?;
+        let cached_len = io
+            .run(move || fs::read_to_string(len_path).ok()?.parse().ok())
+            .await?;
+        let len = match cached_len {
+            Some(len) => len,
+            None => inner.len().await?,
+        };
+        Self::open_with_len(inner, io, root, identity, len, options).await
+    }
+
+    async fn open_with_len(
+        inner: M,
+        io: IoThread,
+        root: PathBuf,
+        identity: &str,
+        len: usize,
+        options: DiskCacheOptions,
+    ) -> Result<Self, MemoryError> {
+        assert!(options.block_size > 0, "block size must be non-zero");
+        let dir = root.join(hex::encode(identity));
+        let cache = Arc::new(CacheDir {
+            root,
+            dir,
+            options,
+            len,
+            approx_size: AtomicU64::new(0),
+            tmp_counter: AtomicU64::new(0),
+            stats: Mutex::default(),
+        });
+        let init = cache.clone();
+        io.run(move || -> io::Result<()> {
+            fs::create_dir_all(&init.dir)?;
+            // The length is written like a block, so a concurrent reader never sees a partial file
+            let _ = init.write_atomically(&init.dir.join(LEN_FILE), len.to_string().as_bytes());
+            init.evict();
+            Ok(())
+        })
+        .await??;
+        Ok(DiskCachedMemory { inner, cache, io })
+    }
+
+    pub fn inner(&self) -> &M { &self.inner }
+
+    /// Hits and misses are counted in blocks. Evictions count the blocks this process deleted,
+    /// including blocks of other archives.
+    pub fn stats(&self) -> CacheStats { *self.cache.stats.lock().unwrap() }
+}
+
+impl<M: Memory> DiskCachedMemory<M> {
+    /// The blocks that `range` overlaps.
+    fn blocks_of(&self, range: &Range<usize>) -> Range<usize> {
+        let block_size = self.cache.options.block_size;
+        match range.is_empty() {
+            true => 0..0,
+            false => range.start / block_size..(range.end - 1) / block_size + 1,
+        }
+    }
+
+    /// Reads `ranges` block by block. The missing blocks of all of them are fetched with one
+    /// `read_slices` of the inner memory, one range per run of consecutive missing blocks.
+    async fn read_cached(&self, ranges: &[Range<usize>]) -> Result<Vec<Vec<u8>>, MemoryError> {
+        let len = self.cache.len;
+        if let Some(range) = ranges.iter().find(|r| r.start > r.end || r.end > len) {
+            return Err(MemoryError::OutOfBounds {
+                range: range.clone(),
+                len,
+            });
+        }
+        let block_size = self.cache.options.block_size;
+        let mut blocks = ranges
+            .iter()
+            .flat_map(|range| self.blocks_of(range))
+            .collect::<Vec<_>>();
+        blocks.sort_unstable();
+        blocks.dedup();
+
+        let cache = self.cache.clone();
+        let (mut cached, missing) = self
+            .io
+            .run(move || {
+                let mut cached = HashMap::new();
+                let mut missing = Vec::new();
+                for block in blocks {
+                    match cache.read_cached_block(block) {
+                        Some(data) => {
+                            cached.insert(block, data);
+                        }
+                        None => missing.push(block),
+                    }
+                }
+                (cached, missing)
+            })
+            .await?;
+        {
+            let mut stats = self.cache.stats.lock().unwrap();
+            stats.misses += missing.len() as u64;
+            stats.hits += cached.len() as u64;
+        }
+
+        // Fetch every run of consecutive missing blocks as one range of a single batch
+        let runs = consecutive_runs(&missing);
+        if !runs.is_empty() {
+            let byte_ranges = runs
+                .iter()
+                .map(|run| self.cache.byte_range(run))
+                .collect::<Vec<_>>();
+            let fetched = runs
+                .into_iter()
+                .zip(read_exact_many(&self.inner, &byte_ranges).await?)
+                .collect::<Vec<_>>();
+
+            // The stored runs come back from the thread to be used for this read
+            let cache = self.cache.clone();
+            let fetched = self
+                .io
+                .run(move || {
+                    for (run, bytes) in &fetched {
+                        for (block, chunk) in run.clone().zip(bytes.chunks(block_size)) {
+                            cache.store_block(block, chunk);
+                        }
+                    }
+                    fetched
+                })
+                .await?;
+            for (run, bytes) in fetched {
+                for (block, chunk) in run.zip(bytes.chunks(block_size)) {
+                    cached.insert(block, chunk.to_vec());
+                }
+            }
+        }
+
+        let mut results = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            let mut result = Vec::with_capacity(range.len());
+            for block in self.blocks_of(range) {
+                extend_from_block(&mut result, range, block * block_size, &cached[&block]);
+            }
+            results.push(result);
+        }
+        Ok(results)
+    }
+}
+
+impl<M: Memory> Memory for DiskCachedMemory<M> {
+    async fn read_slice(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        Ok(self.read_cached(&[range]).await?.pop().unwrap())
+    }
+
+    async fn read_slices(&self, ranges: &[Range<usize>]) -> Result<Vec<Vec<u8>>, MemoryError> {
+        self.read_cached(ranges).await
+    }
+
+    async fn len(&self) -> Result<usize, MemoryError> { Ok(self.cache.len) }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory_view::tests::CountingMemory;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_disk_cache() {
+        let root = tempfile::tempdir().unwrap();
+        let data = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let options = DiskCacheOptions {
+            block_size: 100,
+            ..Default::default()
+        };
+        let ranges = [0..10, 95..105, 150..450, 990..1000, 500..500];
+
+        let memory = DiskCachedMemory::open(
+            CountingMemory::new(data.clone()),
+            root.path(),
+            options.clone(),
+        )
+        .await
+        .unwrap();
+        for range in ranges.clone() {
+            assert_eq!(memory.read_slice(range.clone()).await.unwrap(), data[range]);
+        }
+        // The trailer, then one read per run of missing blocks
+        assert_eq!(memory.inner().reads.get(), 5);
+        assert_eq!(memory.stats().misses, 6);
+
+        // A restarted process only reads the trailer
+        let memory = DiskCachedMemory::open(
+            CountingMemory::new(data.clone()),
+            root.path(),
+            options.clone(),
+        )
+        .await
+        .unwrap();
+        for range in ranges.clone() {
+            assert_eq!(memory.read_slice(range.clone()).await.unwrap(), data[range]);
+        }
+        assert_eq!(memory.inner().reads.get(), 1);
+        assert_eq!(memory.stats().hits, 8);
+
+        // A different archive of the same size doesn't see these blocks
+        let mut other = data.clone();
+        other[996..].copy_from_slice(&[1, 2, 3, 4]);
+        let memory = DiskCachedMemory::open(
+            CountingMemory::new(other.clone()),
+            root.path(),
+            options.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(memory.read_slice(0..10).await.unwrap(), other[0..10]);
+        assert_eq!(memory.inner().reads.get(), 2);
+
+        // With a known identity, reopening reads nothing at all. Corrupt blocks are refetched.
+        let open_etag = || {
+            DiskCachedMemory::open_with_identity(
+                CountingMemory::new(data.clone()),
+                root.path(),
+                "\"etag\"",
+                options.clone(),
+            )
+        };
+        let memory = open_etag().await.unwrap();
+        assert_eq!(memory.read_slice(0..1000).await.unwrap(), data);
+        fs::write(memory.cache.block_path(3), b"short").unwrap();
+        let memory = open_etag().await.unwrap();
+        assert_eq!(memory.read_slice(250..450).await.unwrap(), data[250..450]);
+        assert_eq!(memory.inner().reads.get(), 1);
+        assert_eq!(memory.stats().misses, 1);
+
+        assert!(matches!(
+            memory.read_slice(990..1001).await,
+            Err(MemoryError::OutOfBounds { .. })
+        ));
+
+        // Batched reads fetch the missing blocks of all their ranges with one batch
+        let root = tempfile::tempdir().unwrap();
+        let memory = DiskCachedMemory::open(
+            CountingMemory::new(data.clone()),
+            root.path(),
+            options.clone(),
+        )
+        .await
+        .unwrap();
+        memory.read_slice(100..110).await.unwrap();
+        let ranges = [450..460, 0..10, 95..105, 470..480, 700..700];
+        let slices = memory.read_slices(&ranges).await.unwrap();
+        for (range, slice) in ranges.iter().zip(&slices) {
+            assert_eq!(slice[..], data[range.clone()]);
+        }
+        assert_eq!(memory.inner().batches.get(), 2);
+        assert_eq!(memory.inner().reads.get(), 4);
+        assert_eq!(memory.stats().hits, 1);
+        assert_eq!(memory.stats().misses, 3);
+    }
+
+    #[tokio::test]
+    async fn test_disk_cache_eviction() {
+        let root = tempfile::tempdir().unwrap();
+        let data = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let options = DiskCacheOptions {
+            block_size: 100,
+            max_size: 350,
+        };
+        let cache_size = |root: &Path| {
+            fs::read_dir(root)
+                .unwrap()
+                .flat_map(|dir| fs::read_dir(dir.unwrap().path()).unwrap())
+                .map(|f| f.unwrap())
+                .filter(|f| f.path().extension().is_some_and(|e| e == BLOCK_EXTENSION))
+                .map(|f| f.metadata().unwrap().len())
+                .sum::<u64>()
+        };
+
+        let memory =
+            DiskCachedMemory::open(CountingMemory::new(data.clone()), root.path(), options)
+                .await
+                .unwrap();
+        for i in 0..10 {
+            let start = i * 100;
+            let range = start..start + 100;
+            assert_eq!(memory.read_slice(range.clone()).await.unwrap(), data[range]);
+            assert!(cache_size(root.path()) <= 350);
+        }
+        assert_eq!(memory.stats().evictions, 7);
+        // The most recently read blocks survive
+        let reads = memory.inner().reads.get();
+        assert_eq!(memory.read_slice(900..1000).await.unwrap(), data[900..1000]);
+        assert_eq!(memory.inner().reads.get(), reads);
+
+        // Eviction frees a tenth of the limit, so the next write doesn't have to scan again
+        let root = tempfile::tempdir().unwrap();
+        let options = DiskCacheOptions {
+            block_size: 50,
+            max_size: 500,
+        };
+        let memory =
+            DiskCachedMemory::open(CountingMemory::new(data.clone()), root.path(), options)
+                .await
+                .unwrap();
+        for i in 0..11 {
+            memory.read_slice(i * 50..i * 50 + 1).await.unwrap();
+        }
+        assert_eq!(memory.stats().evictions, 2);
+        assert_eq!(cache_size(root.path()), 450);
+        memory.read_slice(550..551).await.unwrap();
+        assert_eq!(memory.stats().evictions, 2);
+        assert_eq!(cache_size(root.path()), 500);
+    }
+}
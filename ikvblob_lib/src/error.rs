@@ -0,0 +1,111 @@
+use std::{error, io};
+
+use crate::memory_view::MemoryError;
+
+/// Everything that can go wrong when reading an IkvBlob archive.
+///
+/// Apart from `Storage`, every variant means that the archive itself is malformed (or not an
+/// IkvBlob at all), so retrying won't help.
+#[derive(Debug)]
+pub enum IkvblobError {
+    /// The file doesn't start with the IkvBlob magic bytes.
+    BadMagic,
+    /// The file was written by a newer version of the format than this library supports.
+    UnsupportedVersion { found: u64, supported: u64 },
+    /// The file is shorter than its header says it should be.
+    Truncated { expected: u64, actual: u64 },
+    /// The header or the index describes a layout that can't be valid, e.g. overlapping
+    /// sections or a value address pointing outside the value blob.
+    LayoutInvariant(String),
+    /// The dynamic metadata section isn't a well-formed CBOR map, or a reserved key has the
+    /// wrong type.
+    MetadataDecode(String),
+    /// User metadata passed to the writer can't be encoded as a CBOR map with string keys.
+    MetadataEncode(String),
+    /// User metadata passed to the writer tries to set a key reserved for the implementation.
+    ReservedMetadataKey(String),
+    /// The values are compressed with an algorithm this library doesn't know.
+    UnsupportedCompression(String),
+    /// A value could not be decompressed.
+    Decompression(String),
+    /// A value decompresses to more than the limit set with `IkvblobView::with_max_value_size`.
+    /// `size` is the size stored in the frame header, if there is one.
+    ValueTooLarge { size: Option<u64>, limit: usize },
+    /// The CRC32 checksum of the file doesn't match the one stored in its trailer.
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// Reading from the underlying memory failed. Unlike a missing key, this may be worth
+    /// retrying, see `MemoryError::is_transient`.
+    Storage(MemoryError),
+}
+
+impl std::fmt::Display for IkvblobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IkvblobError::BadMagic => write!(f, "IkvblobError: Invalid magic number in header"),
+            IkvblobError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "IkvblobError: Unsupported IkvBlob format version: file has version {}, library supports versions <= {}",
+                found, supported
+            ),
+            IkvblobError::Truncated { expected, actual } => write!(
+                f,
+                "IkvblobError: File is truncated: expected {} bytes, got {}",
+                expected, actual
+            ),
+            IkvblobError::LayoutInvariant(e) => write!(f, "IkvblobError: Invalid layout: {}", e),
+            IkvblobError::MetadataDecode(e) => write!(f, "IkvblobError: Invalid metadata: {}", e),
+            IkvblobError::MetadataEncode(e) => {
+                write!(f, "IkvblobError: Can't encode metadata: {}", e)
+            }
+            IkvblobError::ReservedMetadataKey(k) => {
+                write!(f, "IkvblobError: Metadata key {} is reserved", k)
+            }
+            IkvblobError::UnsupportedCompression(e) => {
+                write!(f, "IkvblobError: Unsupported compression type: {}", e)
+            }
+            IkvblobError::Decompression(e) => {
+                write!(f, "IkvblobError: Decompression failed: {}", e)
+            }
+            IkvblobError::ValueTooLarge { size: Some(size), limit } => write!(
+                f,
+                "IkvblobError: Value of {} bytes is larger than the limit of {} bytes",
+                size, limit
+            ),
+            IkvblobError::ValueTooLarge { size: None, limit } => write!(
+                f,
+                "IkvblobError: Value is larger than the limit of {} bytes",
+                limit
+            ),
+            IkvblobError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "IkvblobError: Checksum mismatch: trailer says {:08x}, contents hash to {:08x}",
+                expected, actual
+            ),
+            IkvblobError::Storage(e) => write!(f, "IkvblobError: {}", e),
+        }
+    }
+}
+
+impl error::Error for IkvblobError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            IkvblobError::Storage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<MemoryError> for IkvblobError {
+    fn from(e: MemoryError) -> Self { IkvblobError::Storage(e) }
+}
+
+// Needed to report errors through `StaticSizeSerializable`, whose methods return `io::Error`
+impl From<IkvblobError> for io::Error {
+    fn from(e: IkvblobError) -> Self {
+        let kind = match e {
+            IkvblobError::Truncated { .. } => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
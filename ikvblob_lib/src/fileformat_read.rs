@@ -1,14 +1,161 @@
-use std::{collections::HashMap, error, hash::Hash};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    hash::Hash,
+    io::{self, Read},
+    ops::Range,
+};
 
-use ciborium::Value;
+use futures::stream::{self, Stream, TryStreamExt};
+use tracing::Instrument;
 
+pub use crate::error::IkvblobError;
 use crate::{
-    fileformat_write::{IkvblobHeader, StaticSizeSerializable},
-    memory_view::Memory,
+    cuckoo,
+    fileformat_write::{
+        IkvblobHeader, StaticSizeSerializable, CHECKSUM_SIZE, PARTITIONED_FORMAT_VERSION,
+    },
+    memory_view::{
+        read_exact, read_exact_blocking, read_exact_many, Memory, MemoryError, SliceMemory,
+        SyncMemory,
+    },
+    metadata::{DynamicMetadata, COMPRESSION_DICT_KEY, COMPRESSION_TYPE_KEY, INDEX_PARTITIONS_KEY},
     parametrized_hasher::SipHasherFactory,
+    utils,
 };
 
-pub struct IkvblobView<'a, M: Memory, K, Idx>
+/// Reads of index buckets or values that are at most this many bytes apart are merged into a
+/// single read by the batched lookup methods.
+pub const COALESCE_GAP_BYTES: usize = 4 * 1024;
+
+/// Default limit on the decompressed size of a value, see `IkvblobView::with_max_value_size`.
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 256 * 1024 * 1024;
+
+/// Number of bytes read at a time by `verify_checksum`.
+pub const VERIFY_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Checks the CRC32 trailer of an archive by streaming the whole archive through the hasher,
+/// `chunk_size` bytes at a time. `progress` is called after every chunk with the number of bytes
+/// checked so far and the total number of bytes to check.
+///
+/// This works on any memory, even one that doesn't hold a valid header, so it can be used to
+/// check an upload before trying to open it.
+pub async fn verify_checksum<M: Memory>(
+    memory: &M,
+    chunk_size: usize,
+    mut progress: impl FnMut(usize, usize),
+) -> Result<(), IkvblobError> {
+    let (chunks, trailer_range) = checksum_chunks(memory.len().await?, chunk_size)?;
+    let content_len = trailer_range.start;
+
+    let mut hasher = crc32fast::Hasher::new();
+    for chunk in chunks {
+        let end = chunk.end;
+        hasher.update(&read_exact(memory, chunk).await?);
+        progress(end, content_len);
+    }
+    check_trailer(&read_exact(memory, trailer_range).await?, hasher)
+}
+
+/// Blocking counterpart of `verify_checksum`.
+pub fn verify_checksum_blocking<M: SyncMemory>(
+    memory: &M,
+    chunk_size: usize,
+    mut progress: impl FnMut(usize, usize),
+) -> Result<(), IkvblobError> {
+    let (chunks, trailer_range) = checksum_chunks(memory.len_blocking()?, chunk_size)?;
+    let content_len = trailer_range.start;
+
+    let mut hasher = crc32fast::Hasher::new();
+    for chunk in chunks {
+        let end = chunk.end;
+        hasher.update(&read_exact_blocking(memory, chunk)?);
+        progress(end, content_len);
+    }
+    check_trailer(&read_exact_blocking(memory, trailer_range)?, hasher)
+}
+
+/// Splits the checksummed part of an archive of `len` bytes into chunks of `chunk_size` bytes,
+/// and returns them together with the range of the CRC32 trailer.
+fn checksum_chunks(
+    len: usize,
+    chunk_size: usize,
+) -> Result<(impl Iterator<Item = Range<usize>>, Range<usize>), IkvblobError> {
+    if len < CHECKSUM_SIZE {
+        return Err(IkvblobError::Truncated {
+            expected: CHECKSUM_SIZE as u64,
+            actual: len as u64,
+        });
+    }
+    let content_len = len - CHECKSUM_SIZE;
+    let chunks = (0..content_len)
+        .step_by(chunk_size.max(1))
+        .map(move |start| start..content_len.min(start + chunk_size.max(1)));
+    Ok((chunks, content_len..len))
+}
+
+fn check_trailer(trailer: &[u8], hasher: crc32fast::Hasher) -> Result<(), IkvblobError> {
+    let expected = u32::from_le_bytes(trailer.try_into().unwrap_or_default());
+    let actual = hasher.finalize();
+    if expected != actual {
+        return Err(IkvblobError::ChecksumMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+/// How a single-key lookup fetches the candidate buckets of a key from the index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LookupStrategy {
+    /// Read all candidate buckets at once and wait for all of them. A lookup then costs a single
+    /// round trip to the index no matter which bucket the key is in. Best for remote storage.
+    #[default]
+    Concurrent,
+    /// Read the candidate buckets one after another and stop as soon as the key is found. Saves
+    /// reads when they are cheap, e.g. for local files.
+    Sequential,
+}
+
+/// Where and how `IkvblobView::scan` and `IkvblobView::scan_addresses` walk the index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// Bucket to start the scan from. Use this to resume a scan that failed, see `ScanEntry`.
+    pub start_bucket: usize,
+    /// Number of consecutive buckets fetched with one read. Together with the value sizes this
+    /// bounds the memory used by the scan.
+    pub buckets_per_read: usize,
+    /// Whether `scan` decompresses values or yields them as stored in the archive.
+    pub decompress: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            start_bucket: 0,
+            buckets_per_read: 1024,
+            decompress: true,
+        }
+    }
+}
+
+/// An entry of the archive, as yielded by a scan.
+///
+/// Buckets are fetched and yielded in order, and a failed read ends the scan before any entry of
+/// the buckets it covers is yielded. So after an error, a scan can be resumed without skipping
+/// or repeating entries by setting `ScanOptions::start_bucket` to one past the `bucket` of the
+/// last entry received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanEntry<K, V> {
+    pub bucket: usize,
+    pub key: K,
+    pub value: V,
+}
+
+/// A read-only view of an IkvBlob archive stored in some memory.
+///
+/// With a `Memory` the view is used through its async methods (`wrap`, `lookup`, ...). With a
+/// `SyncMemory` it can be used without an async runtime through the blocking ones
+/// (`wrap_blocking`, `lookup_blocking`, ...). Both kinds share the same parsing and validation.
+pub struct IkvblobView<'a, M, K, Idx>
 where
     K: Hash + Copy + Eq,
     Option<(K, Idx)>: StaticSizeSerializable,
@@ -17,13 +164,17 @@ where
     source_memory: M,
     compression_dict: Option<Box<zstd::dict::DecoderDictionary<'a>>>,
     hashers: Vec<SipHasherFactory>,
+    index_partitions: usize,
+    metadata: DynamicMetadata,
+    lookup_strategy: LookupStrategy,
+    max_value_size: usize,
     phantom_key: std::marker::PhantomData<K>,
     phantom_idx: std::marker::PhantomData<Idx>,
     phantom_lifetime: std::marker::PhantomData<&'a ()>,
 }
 
 // DecoderDictionary doesn't implement Debug so we implement it manually
-impl<'a, M: Memory + std::fmt::Debug, K, Idx> std::fmt::Debug for IkvblobView<'a, M, K, Idx>
+impl<'a, M: std::fmt::Debug, K, Idx> std::fmt::Debug for IkvblobView<'a, M, K, Idx>
 where
     K: Hash + Copy + Eq,
     Option<(K, Idx)>: StaticSizeSerializable,
@@ -37,78 +188,85 @@ where
             //     &self.compression_dict.as_ref().map(|_| ()),
             // )
             .field("hashers", &self.hashers)
+            .field("index_partitions", &self.index_partitions)
+            .field("metadata", &self.metadata)
+            .field("lookup_strategy", &self.lookup_strategy)
+            .field("max_value_size", &self.max_value_size)
             .finish()
     }
 }
 
-#[derive(Debug)]
-pub enum IkvblobError {
-    Other(String),
-}
-impl std::fmt::Display for IkvblobError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            IkvblobError::Other(e) => write!(f, "IkvblobError: {}", e),
-        }
-    }
-}
-impl std::error::Error for IkvblobError {}
-
-impl<'a, M: Memory, K, Idx> IkvblobView<'a, M, K, Idx>
+impl<'a, M, K, Idx> IkvblobView<'a, M, K, Idx>
 where
     K: Hash + Copy + Eq,
     Option<(K, Idx)>: StaticSizeSerializable,
     K: StaticSizeSerializable,
 {
-    // #[maybe_async::maybe_async]
-    pub async fn wrap(source_memory: M) -> Result<Self, Box<dyn error::Error>> {
-        let len = source_memory.len().await;
-        assert!(len >= IkvblobHeader::SER_SIZE);
+    /// Checks that an archive of `len` bytes is large enough to hold a header, and returns the
+    /// ranges where the header may be: at the start, or in the footer. Both are read at once, so
+    /// that opening an archive in either layout takes a single round of reads.
+    fn header_ranges(len: usize) -> Result<[Range<usize>; 2], IkvblobError> {
+        let min_len = IkvblobHeader::SER_SIZE + CHECKSUM_SIZE;
+        if len < min_len {
+            return Err(IkvblobError::Truncated {
+                expected: min_len as u64,
+                actual: len as u64,
+            });
+        }
+        let footer_start = len - min_len;
+        Ok([
+            0..IkvblobHeader::SER_SIZE,
+            footer_start..footer_start + IkvblobHeader::SER_SIZE,
+        ])
+    }
 
-        let header_bytes = source_memory.read_slice(0..IkvblobHeader::SER_SIZE).await;
-        let header = IkvblobHeader::read(&mut &header_bytes[..]).unwrap();
+    /// Parses the header of an archive of `len` bytes from its start and its footer, and checks
+    /// that it matches both the archive and the key and index types of the view.
+    fn parse_header(
+        start: &[u8],
+        footer: &[u8],
+        len: usize,
+    ) -> Result<IkvblobHeader, IkvblobError> {
+        let header = IkvblobHeader::parse_with_footer(start, footer)?;
 
         if header.cuckoo_entry_size != Option::<(K, Idx)>::SER_SIZE as u64 {
-            return Err(Box::new(IkvblobError::Other(format!(
+            return Err(IkvblobError::LayoutInvariant(format!(
                 "Cuckoo entry size mismatch: expected {}, got {}",
                 Option::<(K, Idx)>::SER_SIZE,
                 header.cuckoo_entry_size
-            ))));
+            )));
         }
-        assert_eq!(header.total_size(), len);
-
-        let hashers = (0..header.cuckoo_table_num_hashers)
-            .map(|i| SipHasherFactory::new(i))
-            .collect();
-
-        let md = source_memory
-            .read_slice(
-                header.dynamic_metadata_offset as usize
-                    ..(header.dynamic_metadata_offset + header.dynamic_metadata_size) as usize,
-            )
-            .await;
-        let md_cbor = ciborium::from_reader::<ciborium::Value, _>(&md[..])?;
-        if !md_cbor.is_map() {
-            return Err(Box::new(IkvblobError::Other(
-                "Metadata is not a map".to_string(),
+        if header.total_size() > len {
+            return Err(IkvblobError::Truncated {
+                expected: header.total_size() as u64,
+                actual: len as u64,
+            });
+        }
+        if header.total_size() < len {
+            return Err(IkvblobError::LayoutInvariant(format!(
+                "File has {} bytes, but its header describes {} bytes",
+                len,
+                header.total_size()
             )));
         }
-        let md_map: HashMap<String, &Value> = md_cbor
-            .as_map()
-            .unwrap()
-            .into_iter()
-            .map(|(k, v)| {
-                if k.is_text() {
-                    Ok((k.as_text().unwrap().to_string(), v))
-                } else {
-                    Err(Box::new(IkvblobError::Other(
-                        "Metadata key is not a string".to_string(),
-                    )))
-                }
-            })
-            .collect::<Result<Vec<(String, &Value)>, _>>()?
-            .into_iter()
+        Ok(header)
+    }
+
+    fn metadata_range(header: &IkvblobHeader) -> Range<usize> {
+        header.dynamic_metadata_offset as usize
+            ..(header.dynamic_metadata_offset + header.dynamic_metadata_size) as usize
+    }
+
+    /// Builds the view from the parsed header and the raw metadata section.
+    fn from_parts(
+        header: IkvblobHeader,
+        source_memory: M,
+        metadata_bytes: &[u8],
+    ) -> Result<Self, IkvblobError> {
+        let hashers = (0..header.cuckoo_table_num_hashers)
+            .map(SipHasherFactory::new)
             .collect();
+        let metadata = DynamicMetadata::decode(metadata_bytes)?;
 
         // let md_bson = bson::Document::from_reader(&md[..])?;
 
@@ -116,184 +274,603 @@ where
         // "compression_dict": bson::Binary {subtype: bson::spec::BinarySubtype::Generic, bytes: compression_dict.to_vec() },
         // let compression_dict = ;
 
-        Self::new_with_compression_dict(header, source_memory, hashers, md_map)
+        Self::new_with_compression_dict(header, source_memory, hashers, metadata)
     }
 
     fn new_with_compression_dict(
         header: IkvblobHeader,
         source_memory: M,
         hashers: Vec<SipHasherFactory>,
-        md_map: HashMap<String, &Value>,
-    ) -> Result<Self, Box<dyn error::Error>> {
-        let compression_dict =
-            if md_map.contains_key("compression_type") && md_map.contains_key("compression_dict") {
-                let compression_type =
-                    md_map["compression_type"]
-                        .as_text()
-                        .ok_or(Box::new(IkvblobError::Other(
-                            "compression_type is not a string".to_string(),
-                        )))?;
+        metadata: DynamicMetadata,
+    ) -> Result<Self, IkvblobError> {
+        let compression_dict = match (
+            metadata.reserved(COMPRESSION_TYPE_KEY),
+            metadata.reserved(COMPRESSION_DICT_KEY),
+        ) {
+            (None, None) => None,
+            (Some(compression_type), Some(compression_dict)) => {
+                let compression_type = compression_type.as_text().ok_or_else(|| {
+                    IkvblobError::MetadataDecode("compression_type is not a string".to_string())
+                })?;
                 if compression_type != "zstd" {
-                    return Err(Box::new(IkvblobError::Other(format!(
-                        "Unsupported compression type: {}",
-                        compression_type
-                    ))));
+                    return Err(IkvblobError::UnsupportedCompression(
+                        compression_type.to_string(),
+                    ));
                 }
-                let compression_dict_bytes =
-                    md_map["compression_dict"]
-                        .as_bytes()
-                        .ok_or(Box::new(IkvblobError::Other(
-                            "compression_dict is not a binary".to_string(),
-                        )))?;
+                let compression_dict_bytes = compression_dict.as_bytes().ok_or_else(|| {
+                    IkvblobError::MetadataDecode("compression_dict is not a binary".to_string())
+                })?;
                 Some(Box::new(zstd::dict::DecoderDictionary::copy(
-                    &compression_dict_bytes,
+                    compression_dict_bytes,
                 )))
-            } else {
-                None
-            };
+            }
+            _ => {
+                return Err(IkvblobError::MetadataDecode(
+                    "compression_type and compression_dict must be present together".to_string(),
+                ))
+            }
+        };
+        let index_partitions = Self::index_partitions(&header, &metadata)?;
         Ok(IkvblobView {
             header,
             compression_dict,
             source_memory,
             hashers,
+            index_partitions,
+            metadata,
+            lookup_strategy: LookupStrategy::default(),
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
             phantom_key: std::marker::PhantomData,
             phantom_idx: std::marker::PhantomData,
             phantom_lifetime: std::marker::PhantomData,
         })
     }
 
-    // #[maybe_async::maybe_async]
-    async fn get_hashmap_bucket(&self, idx: usize) -> Result<Vec<Option<(K, Idx)>>, IkvblobError> {
-        log::debug!("{}", idx);
+    /// Number of regions the index is split into, which must divide its buckets evenly. Only
+    /// archives of version 3 are partitioned, and the key is ignored in older ones.
+    fn index_partitions(
+        header: &IkvblobHeader,
+        metadata: &DynamicMetadata,
+    ) -> Result<usize, IkvblobError> {
+        if header.version < PARTITIONED_FORMAT_VERSION {
+            return Ok(1);
+        }
+        let partitions = metadata
+            .reserved(INDEX_PARTITIONS_KEY)
+            .and_then(|p| p.as_integer())
+            .and_then(|p| usize::try_from(p).ok())
+            .filter(|p| *p > 0)
+            .ok_or_else(|| {
+                IkvblobError::MetadataDecode(
+                    "index_partitions is missing or not a positive integer".to_string(),
+                )
+            })?;
+        let num_buckets = header.cuckoo_table_num_buckets();
+        if !num_buckets.is_multiple_of(partitions) {
+            return Err(IkvblobError::LayoutInvariant(format!(
+                "{} index buckets can't be split into {} partitions",
+                num_buckets, partitions
+            )));
+        }
+        Ok(partitions)
+    }
+
+    /// The dynamic metadata of the archive. Reserved keys are only reachable through
+    /// `DynamicMetadata::reserved`.
+    pub fn metadata(&self) -> &DynamicMetadata { &self.metadata }
+
+    /// Sets how single-key lookups read the index, see `LookupStrategy`.
+    pub fn with_lookup_strategy(mut self, lookup_strategy: LookupStrategy) -> Self {
+        self.lookup_strategy = lookup_strategy;
+        self
+    }
+
+    /// Sets the largest decompressed value size that is accepted, so that a corrupt or
+    /// malicious archive can't make a lookup allocate unbounded memory. Larger values fail with
+    /// `IkvblobError::ValueTooLarge`. Defaults to `DEFAULT_MAX_VALUE_SIZE`.
+    pub fn with_max_value_size(mut self, max_value_size: usize) -> Self {
+        self.max_value_size = max_value_size;
+        self
+    }
+
+    fn bucket_byte_size(&self) -> usize {
+        Option::<(K, Idx)>::SER_SIZE * self.header.cuckoo_table_elems_per_bucket as usize
+    }
+
+    fn bucket_range(&self, idx: usize) -> Range<usize> {
+        let start = self.header.cuckoo_table_offset as usize + idx * self.bucket_byte_size();
+        start..start + self.bucket_byte_size()
+    }
+
+    fn candidate_buckets(&self, key: &K) -> impl Iterator<Item = usize> + '_ {
+        let num_buckets = self.header.cuckoo_table_num_buckets();
+        let region = cuckoo::region_of(key, num_buckets, self.index_partitions);
+        let region_len = region.len() as u64;
+        let key = *key;
+        // An empty table has no candidate buckets
+        self.hashers
+            .iter()
+            .filter(move |_| region_len > 0)
+            .map(move |hasher| region.start + (hasher.hash(&key) % region_len) as usize)
+    }
 
-        // fn get_hashmap_bucket(&self, idx: usize) -> Result<impl Iterator<Item = Option<(K, Idx)> >, IkvblobError> {
-        let start = (self.header.cuckoo_table_offset
-            + (idx
-                * Option::<(K, Idx)>::SER_SIZE
-                * self.header.cuckoo_table_elems_per_bucket as usize) as u64)
-            as usize;
-        let end = start
-            + (Option::<(K, Idx)>::SER_SIZE * self.header.cuckoo_table_elems_per_bucket as usize)
-                as usize;
-        let slice = self.source_memory.read_slice(start..end).await;
-        let mut reader = &slice[..];
-
-        // (0..self.header.cuckoo_table_elems_per_bucket)
-        //     .map(|_| Option::<(K, Idx)>::read(&mut reader).unwrap())
-        //     .map(|x| x.map_err(|e| IkvblobError::Other(e.to_string())))
-        //     .collect::<Result<Vec<_>, _>>()
-        //     .map(|x| x.into_iter())
+    fn read_entry(reader: &mut &[u8]) -> Result<Option<(K, Idx)>, IkvblobError> {
+        Option::<(K, Idx)>::read(reader)
+            .map_err(|e| IkvblobError::LayoutInvariant(format!("Invalid index entry: {}", e)))
+    }
+
+    fn decode_bucket(&self, slice: &[u8]) -> Result<Vec<Option<(K, Idx)>>, IkvblobError> {
+        let mut reader = slice;
         let mut result = Vec::new();
         for _ in 0..self.header.cuckoo_table_elems_per_bucket {
-            result.push(
-                Option::<(K, Idx)>::read(&mut reader)
-                    .map_err(|e| IkvblobError::Other(e.to_string()))?,
-            );
+            result.push(Self::read_entry(&mut reader)?);
         }
         Ok(result)
     }
 
+    /// Like `find_in_bucket` on the decoded bucket, but decodes one entry at a time and stops
+    /// at the key, without allocating.
+    fn find_in_bucket_bytes(&self, slice: &[u8], key: &K) -> Result<Option<Idx>, IkvblobError> {
+        let mut reader = slice;
+        for _ in 0..self.header.cuckoo_table_elems_per_bucket {
+            match Self::read_entry(&mut reader)? {
+                Some((k, v)) if &k == key => return Ok(Some(v)),
+                _ => continue,
+            }
+        }
+        Ok(None)
+    }
+
+    fn find_in_bucket(bucket: &[Option<(K, Idx)>], key: &K) -> Option<Idx>
+    where
+        Idx: Clone,
+    {
+        bucket.iter().find_map(|entry| match entry {
+            Some((k, v)) if k == key => Some(v.clone()),
+            _ => None,
+        })
+    }
+
+    fn slice_from_coalesced<'b>(
+        merged: &[Range<usize>],
+        data: &'b [Vec<u8>],
+        range: Range<usize>,
+    ) -> Result<&'b [u8], IkvblobError> {
+        utils::slice_from_coalesced(merged, data, range.clone())
+            .ok_or(IkvblobError::Storage(MemoryError::ShortRead { range, got: 0 }))
+    }
+}
+
+impl<'a, M: Memory, K, Idx> IkvblobView<'a, M, K, Idx>
+where
+    K: Hash + Copy + Eq,
+    Option<(K, Idx)>: StaticSizeSerializable,
+    K: StaticSizeSerializable,
+{
     // #[maybe_async::maybe_async]
-    async fn lookup_value_address(&self, key: &K) -> Option<Idx> {
-        for hasher in &self.hashers {
-            let idx = (hasher.hash(key) % (self.header.cuckoo_table_num_buckets() as u64)) as usize;
-            log::debug!("hash: {}", hasher.hash(key));
-            log::debug!("num_buckets: {}", self.header.cuckoo_table_num_buckets());
-            log::debug!("idx: {}", idx);
-            let bucket = self.get_hashmap_bucket(idx).await.unwrap();
-            for entry in bucket {
-                match entry {
-                    Some((k, v)) if &k == key => return Some(v),
-                    _ => continue,
+    #[tracing::instrument(name = "ikvblob_wrap", level = "debug", skip_all)]
+    pub async fn wrap(source_memory: M) -> Result<Self, IkvblobError> {
+        let len = source_memory.len().await?;
+        let [start, footer] = read_exact_many(&source_memory, &Self::header_ranges(len)?)
+            .await?
+            .try_into()
+            .expect("one slice per range");
+        let header = Self::parse_header(&start, &footer, len)?;
+        let md = read_exact(&source_memory, Self::metadata_range(&header)).await?;
+        Self::from_parts(header, source_memory, &md)
+    }
+
+    /// Checks the whole archive against its CRC32 trailer, see `verify_checksum`.
+    pub async fn verify(&self, progress: impl FnMut(usize, usize)) -> Result<(), IkvblobError> {
+        verify_checksum(&self.source_memory, VERIFY_CHUNK_SIZE, progress).await
+    }
+
+    // #[maybe_async::maybe_async]
+    #[tracing::instrument(name = "ikvblob_fetch_bucket", level = "debug", skip(self))]
+    async fn get_hashmap_bucket(&self, idx: usize) -> Result<Vec<Option<(K, Idx)>>, IkvblobError> {
+        log::debug!("{}", idx);
+
+        let slice = read_exact(&self.source_memory, self.bucket_range(idx)).await?;
+        self.decode_bucket(&slice)
+    }
+
+    /// Reads the given byte ranges of the source memory, merging the ones that are close to
+    /// each other, and issues all the resulting reads with one `Memory::read_slices`.
+    async fn read_coalesced(
+        &self,
+        ranges: Vec<Range<usize>>,
+    ) -> Result<(Vec<Range<usize>>, Vec<Vec<u8>>), MemoryError> {
+        let merged = utils::coalesce_ranges(ranges, COALESCE_GAP_BYTES);
+        let data = read_exact_many(&self.source_memory, &merged).await?;
+        Ok((merged, data))
+    }
+
+    /// Finds the value addresses of many keys at once. All candidate buckets of all keys are
+    /// fetched together, so this costs one round of reads regardless of the number of keys.
+    #[tracing::instrument(
+        name = "ikvblob_fetch_buckets",
+        level = "debug",
+        skip_all,
+        fields(keys = keys.len())
+    )]
+    async fn lookup_value_addresses(&self, keys: &[K]) -> Result<Vec<Option<Idx>>, IkvblobError>
+    where
+        Idx: Clone,
+    {
+        let mut bucket_idxs = keys
+            .iter()
+            .flat_map(|key| self.candidate_buckets(key))
+            .collect::<Vec<_>>();
+        bucket_idxs.sort_unstable();
+        bucket_idxs.dedup();
+
+        let (merged, data) = self
+            .read_coalesced(bucket_idxs.iter().map(|&i| self.bucket_range(i)).collect())
+            .await?;
+
+        let mut buckets = HashMap::with_capacity(bucket_idxs.len());
+        for i in bucket_idxs {
+            let slice = Self::slice_from_coalesced(&merged, &data, self.bucket_range(i))?;
+            buckets.insert(i, self.decode_bucket(slice)?);
+        }
+
+        Ok(keys
+            .iter()
+            .map(|key| {
+                self.candidate_buckets(key)
+                    .find_map(|i| Self::find_in_bucket(&buckets[&i], key))
+            })
+            .collect())
+    }
+
+    // #[maybe_async::maybe_async]
+    async fn lookup_value_address(&self, key: &K) -> Result<Option<Idx>, IkvblobError>
+    where
+        Idx: Clone,
+    {
+        match self.lookup_strategy {
+            LookupStrategy::Concurrent => Ok(self
+                .lookup_value_addresses(std::slice::from_ref(key))
+                .await?
+                .pop()
+                .flatten()),
+            LookupStrategy::Sequential => {
+                for idx in self.candidate_buckets(key) {
+                    log::debug!("idx: {}", idx);
+                    let bucket = self.get_hashmap_bucket(idx).await?;
+                    if let Some(v) = Self::find_in_bucket(&bucket, key) {
+                        return Ok(Some(v));
+                    }
                 }
+                Ok(None)
             }
         }
-        None
+    }
+
+    fn scan_batches(
+        &self,
+        options: &ScanOptions,
+    ) -> impl Stream<Item = Result<Vec<ScanEntry<K, Idx>>, IkvblobError>> + '_ {
+        let num_buckets = self.header.cuckoo_table_num_buckets();
+        let buckets_per_read = options.buckets_per_read.max(1);
+        stream::try_unfold(options.start_bucket, move |start| async move {
+            if start >= num_buckets {
+                return Ok(None);
+            }
+            let end = num_buckets.min(start + buckets_per_read);
+            let bytes = read_exact(
+                &self.source_memory,
+                self.bucket_range(start).start..self.bucket_range(end - 1).end,
+            )
+            .await?;
+
+            let mut entries = Vec::new();
+            for (bucket, slice) in (start..end).zip(bytes.chunks_exact(self.bucket_byte_size())) {
+                for (key, value) in self.decode_bucket(slice)?.into_iter().flatten() {
+                    entries.push(ScanEntry { bucket, key, value });
+                }
+            }
+            Ok(Some((entries, end)))
+        })
+    }
+
+    /// Streams all keys of the archive together with their value addresses, walking the index
+    /// bucket by bucket. See `ScanEntry` for how to resume a scan after an error.
+    pub fn scan_addresses(
+        &self,
+        options: &ScanOptions,
+    ) -> impl Stream<Item = Result<ScanEntry<K, Idx>, IkvblobError>> + '_ {
+        self.scan_batches(options)
+            .map_ok(|batch| stream::iter(batch.into_iter().map(Ok)))
+            .try_flatten()
     }
 
     // #[maybe_async::maybe_async]
-    async fn _read_debug(&self) -> (IkvblobHeader, Vec<Vec<Option<(K, Idx)>>>, Vec<u8>) {
+    async fn _read_debug(
+        &self,
+    ) -> Result<(IkvblobHeader, Vec<Vec<Option<(K, Idx)>>>, Vec<u8>), IkvblobError> {
         let mut cuckoo_table = Vec::new();
         for i in 0..self.header.cuckoo_table_num_buckets() {
-            let bucket = self.get_hashmap_bucket(i as usize).await.unwrap();
+            let bucket = self.get_hashmap_bucket(i).await?;
             cuckoo_table.push(bucket);
         }
 
         let mut value_blob = Vec::new();
         let start = self.header.value_blob_offset as usize;
         let end = start + self.header.value_blob_size as usize;
-        value_blob.extend_from_slice(&self.source_memory.read_slice(start..end).await);
+        value_blob.extend_from_slice(&read_exact(&self.source_memory, start..end).await?);
 
-        (self.header.clone(), cuckoo_table, value_blob)
+        Ok((self.header.clone(), cuckoo_table, value_blob))
     }
 }
 
-impl<'a, M: Memory, K: std::fmt::Debug> IkvblobView<'a, M, K, (u64, u64)>
+impl<'a, M: SyncMemory, K, Idx> IkvblobView<'a, M, K, Idx>
+where
+    K: Hash + Copy + Eq,
+    Option<(K, Idx)>: StaticSizeSerializable,
+    K: StaticSizeSerializable,
+{
+    /// Blocking counterpart of `wrap`.
+    #[tracing::instrument(name = "ikvblob_wrap", level = "debug", skip_all)]
+    pub fn wrap_blocking(source_memory: M) -> Result<Self, IkvblobError> {
+        let len = source_memory.len_blocking()?;
+        let [start, footer] = Self::header_ranges(len)?;
+        let start = read_exact_blocking(&source_memory, start)?;
+        let footer = read_exact_blocking(&source_memory, footer)?;
+        let header = Self::parse_header(&start, &footer, len)?;
+        let md = read_exact_blocking(&source_memory, Self::metadata_range(&header))?;
+        Self::from_parts(header, source_memory, &md)
+    }
+
+    /// Blocking counterpart of `verify`.
+    pub fn verify_blocking(&self, progress: impl FnMut(usize, usize)) -> Result<(), IkvblobError> {
+        verify_checksum_blocking(&self.source_memory, VERIFY_CHUNK_SIZE, progress)
+    }
+
+    /// Reads the candidate buckets one after another, whatever the lookup strategy, as there is
+    /// nothing to gain from issuing blocking reads together.
+    #[tracing::instrument(name = "ikvblob_fetch_buckets", level = "debug", skip_all)]
+    fn lookup_value_address_blocking(&self, key: &K) -> Result<Option<Idx>, IkvblobError>
+    where
+        Idx: Clone,
+    {
+        for idx in self.candidate_buckets(key) {
+            let slice = read_exact_blocking(&self.source_memory, self.bucket_range(idx))?;
+            if let Some(v) = Self::find_in_bucket(&self.decode_bucket(&slice)?, key) {
+                return Ok(Some(v));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a, M, K: std::fmt::Debug> IkvblobView<'a, M, K, (u64, u64)>
 where
     K: Hash + Copy + Eq,
     Option<(K, (u64, u64))>: StaticSizeSerializable,
     K: StaticSizeSerializable,
 {
-    fn try_decompress(&self, bytes: &[u8]) -> Result<Vec<u8>, IkvblobError> {
-        match &self.compression_dict {
-            None => Ok(bytes.into()),
-            Some(dict) => {
-                let mut decoder =
-                    zstd::bulk::Decompressor::with_prepared_dictionary(&dict).unwrap();
-
-                // try decoding into an ever higher capacity buffer until it works
-                let starting_buffer = 4 * 1024;
-                for i in 0..6 {
-                    let mut result = Vec::with_capacity(starting_buffer * (2 << i));
-                    match decoder.decompress_to_buffer(&bytes, &mut result) {
-                        Ok(_) => return Ok(result),
-                        Err(_) => continue,
-                    }
+    /// Decompresses a value, or returns it as it is if the archive isn't compressed.
+    fn try_decompress<'b>(&self, bytes: &'b [u8]) -> Result<Cow<'b, [u8]>, IkvblobError> {
+        let dict = match &self.compression_dict {
+            None => return Ok(Cow::Borrowed(bytes)),
+            Some(dict) => dict,
+        };
+        let _span = tracing::debug_span!("ikvblob_decompress", size = bytes.len()).entered();
+        let decompression_error = |e: io::Error| IkvblobError::Decompression(e.to_string());
+        let limit = self.max_value_size;
+
+        match zstd::zstd_safe::get_frame_content_size(bytes) {
+            // The frame header tells the exact size, so decompress in one go
+            Ok(Some(size)) => {
+                if size > limit as u64 {
+                    return Err(IkvblobError::ValueTooLarge {
+                        size: Some(size),
+                        limit,
+                    });
+                }
+                let mut decoder = zstd::bulk::Decompressor::with_prepared_dictionary(dict)
+                    .map_err(decompression_error)?;
+                let mut result = Vec::with_capacity(size as usize);
+                decoder
+                    .decompress_to_buffer(bytes, &mut result)
+                    .map_err(decompression_error)?;
+                Ok(Cow::Owned(result))
+            }
+            // Frames written without their size, e.g. by a streaming compressor, are streamed
+            // into a growing buffer, stopping as soon as the limit is exceeded
+            Ok(None) => {
+                let decoder = zstd::stream::read::Decoder::with_prepared_dictionary(bytes, dict)
+                    .map_err(decompression_error)?;
+                let mut result = Vec::new();
+                decoder
+                    .take(limit as u64 + 1)
+                    .read_to_end(&mut result)
+                    .map_err(decompression_error)?;
+                if result.len() > limit {
+                    return Err(IkvblobError::ValueTooLarge { size: None, limit });
                 }
+                Ok(Cow::Owned(result))
+            }
+            Err(_) => Err(IkvblobError::Decompression(
+                "value is not a zstd frame".to_string(),
+            )),
+        }
+    }
+
+    /// Like `try_decompress`, but doesn't copy values of uncompressed archives.
+    fn decompress_owned(&self, bytes: Vec<u8>) -> Result<Vec<u8>, IkvblobError> {
+        match self.compression_dict {
+            None => Ok(bytes),
+            Some(_) => self.try_decompress(&bytes).map(Cow::into_owned),
+        }
+    }
 
-                Err(IkvblobError::Other("Decompression failed".to_string()))
+    /// Returns the byte range of the value at the given address, checking that it lies within
+    /// the value blob.
+    fn value_range(&self, (offset, size): (u64, u64)) -> Result<Range<usize>, IkvblobError> {
+        match offset.checked_add(size) {
+            Some(end) if end <= self.header.value_blob_size => {
+                let start = (self.header.value_blob_offset + offset) as usize;
+                Ok(start..start + size as usize)
             }
+            _ => Err(IkvblobError::LayoutInvariant(format!(
+                "Value address ({}, {}) is outside of the value blob",
+                offset, size
+            ))),
         }
     }
+}
 
+impl<'a, M: Memory, K: std::fmt::Debug> IkvblobView<'a, M, K, (u64, u64)>
+where
+    K: Hash + Copy + Eq,
+    Option<(K, (u64, u64))>: StaticSizeSerializable,
+    K: StaticSizeSerializable,
+{
     pub async fn lookup(&self, key: &K) -> Result<Option<Vec<u8>>, IkvblobError> {
         log::debug!("{:?}", key);
-        let (offset, size) = match self.lookup_value_address(key).await {
+        let address = match self.lookup_value_address(key).await? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+
+        let range = self.value_range(address)?;
+        let span = tracing::debug_span!("ikvblob_fetch_value", size = range.len());
+        let raw_bytes = read_exact(&self.source_memory, range)
+            .instrument(span)
+            .await?;
+        self.decompress_owned(raw_bytes).map(Some)
+    }
+
+    /// Streams all entries of the archive with their values, walking the index bucket by bucket
+    /// and fetching the values of every batch of buckets together. See `ScanEntry` for how to
+    /// resume a scan after an error.
+    pub fn scan(
+        &self,
+        options: &ScanOptions,
+    ) -> impl Stream<Item = Result<ScanEntry<K, Vec<u8>>, IkvblobError>> + '_ {
+        let decompress = options.decompress;
+        self.scan_batches(options)
+            .and_then(move |batch| async move {
+                let ranges = batch
+                    .iter()
+                    .map(|entry| self.value_range(entry.value))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let (merged, data) = self.read_coalesced(ranges.clone()).await?;
+                batch
+                    .into_iter()
+                    .zip(ranges)
+                    .map(|(entry, range)| {
+                        let raw_bytes = Self::slice_from_coalesced(&merged, &data, range)?;
+                        Ok(ScanEntry {
+                            bucket: entry.bucket,
+                            key: entry.key,
+                            value: if decompress {
+                                self.try_decompress(raw_bytes)?.into_owned()
+                            } else {
+                                raw_bytes.to_vec()
+                            },
+                        })
+                    })
+                    .collect::<Result<Vec<_>, IkvblobError>>()
+            })
+            .map_ok(|batch| stream::iter(batch.into_iter().map(Ok)))
+            .try_flatten()
+    }
+
+    /// Looks up many keys at once and returns their values in the order of `keys`.
+    ///
+    /// Unlike calling `lookup` in a loop, which costs two sequential round trips per key, this
+    /// fetches the candidate buckets of all keys in one round of concurrent reads and then all
+    /// the values in a second one. Reads of nearby regions are merged, see
+    /// `COALESCE_GAP_BYTES`.
+    pub async fn lookup_many(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>, IkvblobError> {
+        let ranges = self
+            .lookup_value_addresses(keys)
+            .await?
+            .into_iter()
+            .map(|address| address.map(|a| self.value_range(a)).transpose())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let span = tracing::debug_span!("ikvblob_fetch_values", keys = keys.len());
+        let (merged, data) = self
+            .read_coalesced(ranges.iter().flatten().cloned().collect())
+            .instrument(span)
+            .await?;
+
+        ranges
+            .into_iter()
+            .map(|range| match range {
+                None => Ok(None),
+                Some(range) => {
+                    let raw_bytes = Self::slice_from_coalesced(&merged, &data, range)?;
+                    self.try_decompress(raw_bytes).map(|v| Some(v.into_owned()))
+                }
+            })
+            .collect()
+    }
+}
+
+impl<'a, M: SyncMemory, K: std::fmt::Debug> IkvblobView<'a, M, K, (u64, u64)>
+where
+    K: Hash + Copy + Eq,
+    Option<(K, (u64, u64))>: StaticSizeSerializable,
+    K: StaticSizeSerializable,
+{
+    /// Blocking counterpart of `lookup`.
+    pub fn lookup_blocking(&self, key: &K) -> Result<Option<Vec<u8>>, IkvblobError> {
+        let address = match self.lookup_value_address_blocking(key)? {
             Some(v) => v,
             None => return Ok(None),
         };
 
-        let start = (self.header.value_blob_offset + offset) as usize;
-        let end = start + size as usize;
-
-        let raw_bytes = self.source_memory.read_slice(start..end).await;
-        self.try_decompress(&raw_bytes).map(Some)
-        // match &self.compression_dict {
-        //     None => Ok(Some(raw_bytes.into())),
-        //     Some(dict) => {
-        //         let mut decoder =
-        //             zstd::bulk::Decompressor::with_prepared_dictionary(&dict).unwrap();
-
-        //         // try decoding into an ever higher capacity buffer until it works
-        //         let starting_buffer = 4 * 1024;
-        //         for i in 0..6 {
-        //             let mut result = Vec::with_capacity(starting_buffer * (2 << i));
-        //             match decoder.decompress_to_buffer(&raw_bytes, &mut result) {
-        //                 Ok(_) => return Ok(Some(result)),
-        //                 Err(_) => continue,
-        //             }
-        //         }
-
-        //         Err(IkvblobError::Other("Decompression failed".to_string()))
-        //     }
-        // }
+        let range = self.value_range(address)?;
+        let raw_bytes = {
+            let _span = tracing::debug_span!("ikvblob_fetch_value", size = range.len()).entered();
+            read_exact_blocking(&self.source_memory, range)?
+        };
+        self.decompress_owned(raw_bytes).map(Some)
+    }
+}
+
+impl<'a, M: SliceMemory, K: std::fmt::Debug> IkvblobView<'a, M, K, (u64, u64)>
+where
+    K: Hash + Copy + Eq,
+    Option<(K, (u64, u64))>: StaticSizeSerializable,
+    K: StaticSizeSerializable,
+{
+    /// Looks up a key in an archive that is entirely in memory, e.g. a `Vec<u8>` or an
+    /// `MmapMemory`. Nothing is copied or allocated: the value is borrowed straight from the
+    /// memory, unless the archive is compressed and the value has to be decompressed.
+    pub fn lookup_ref(&self, key: &K) -> Result<Option<Cow<'_, [u8]>>, IkvblobError> {
+        let bytes = self.source_memory.as_bytes();
+        let slice = |range: Range<usize>| {
+            bytes.get(range.clone()).ok_or(IkvblobError::Storage(MemoryError::OutOfBounds {
+                range,
+                len: bytes.len(),
+            }))
+        };
+
+        for idx in self.candidate_buckets(key) {
+            if let Some(address) = self.find_in_bucket_bytes(slice(self.bucket_range(idx))?, key)? {
+                return self.try_decompress(slice(self.value_range(address)?)?).map(Some);
+            }
+        }
+        Ok(None)
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
+    use ciborium::{cbor, Value};
+    use futures::StreamExt;
+
     use crate::{
-        cuckoo::StaticCuckooTable, fileformat_write::write_combined_file, multihash::Multihash,
+        cuckoo::StaticCuckooTable,
+        fileformat_write::{write_combined_file, StreamingWriter},
+        memory_view::tests::CountingMemory,
+        multihash::Multihash,
     };
 
     use super::*;
@@ -312,11 +889,12 @@ mod tests {
         let data = (0..test_size).map(|x| x as u8).collect::<Vec<u8>>();
 
         let mut buf = Vec::new();
-        write_combined_file(&table, &Vec::new(), &data[..], data.len(), &mut buf).unwrap();
+        write_combined_file(&table, &Vec::new(), &(), &data[..], data.len(), &mut buf).unwrap();
 
         let view = IkvblobView::wrap(buf).await.unwrap();
+        assert_eq!(view.metadata().user_keys().count(), 0);
 
-        let (_, cuckoo_reconstr, _) = view._read_debug().await;
+        let (_, cuckoo_reconstr, _) = view._read_debug().await.unwrap();
         let reconstr_tpd = cuckoo_reconstr
             .into_iter()
             .map(|x| TryInto::<[Option<(Multihash<32>, (u64, u64))>; 8]>::try_into(x).unwrap())
@@ -335,4 +913,525 @@ mod tests {
             assert_eq!(value, vec![i as u8]);
         }
     }
+
+    /// Key `i` of `build_test_file`.
+    pub(crate) fn test_key(i: u32) -> Multihash<32> {
+        Multihash::<32>::wrap(2, i.to_le_bytes().repeat(8).try_into().unwrap())
+    }
+
+    /// An archive of `test_size` keys, in which `test_key(i)` maps to the single byte `i as u8`.
+    /// Also used by the tests of the memory backends and wrappers.
+    pub(crate) fn build_test_file(test_size: u32) -> Vec<u8> {
+        let kvs = (0..test_size).map(|i| (test_key(i), (i as u64, 1)));
+        let table = StaticCuckooTable::<8, 2, _, _>::from_iter(kvs, 1.2);
+        let data = (0..test_size).map(|x| x as u8).collect::<Vec<u8>>();
+        let mut buf = Vec::new();
+        write_combined_file(&table, &Vec::new(), &(), &data[..], data.len(), &mut buf).unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_lookup_strategies() {
+        let test_size = 2000u32;
+        let key = |i: u32| Multihash::<32>::wrap(2, i.to_le_bytes().repeat(8).try_into().unwrap());
+
+        let view = IkvblobView::<_, Multihash<32>, (u64, u64)>::wrap(CountingMemory::new(
+            build_test_file(test_size),
+        ))
+        .await
+        .unwrap();
+        let mem = &view.source_memory;
+
+        // Both candidate buckets of a missing key have to be read. They are requested together,
+        // unless they are close enough to be merged into one read.
+        for i in test_size..test_size + 20 {
+            mem.reset();
+            assert_eq!(view.lookup(&key(i)).await.unwrap(), None);
+            assert_eq!(mem.max_in_flight.get(), mem.reads.get());
+        }
+
+        let view = view.with_lookup_strategy(LookupStrategy::Sequential);
+        let mem = &view.source_memory;
+        let mut short_circuited = 0;
+        for i in 0..test_size {
+            mem.reset();
+            assert_eq!(view.lookup(&key(i)).await.unwrap(), Some(vec![i as u8]));
+            assert_eq!(mem.max_in_flight.get(), 1);
+            // One read for the first bucket, possibly one for the second, one for the value
+            if mem.reads.get() == 2 {
+                short_circuited += 1;
+            }
+        }
+        assert!(short_circuited > 0);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_many() {
+        // Value i consists of (i % 7 + 1) copies of the byte i
+        let test_size = 100u32;
+        let value_len = |i: u64| i % 7 + 1;
+        let kvs = (0..test_size).map(|i| {
+            let i = i as u64;
+            let offset = (0..i).map(value_len).sum::<u64>();
+            (Multihash::<32>::wrap(2, [i as u8; 32]), (offset, value_len(i)))
+        });
+        let table = StaticCuckooTable::<8, 2, _, _>::from_iter(kvs, 1.2);
+        let data = (0..test_size)
+            .flat_map(|i| std::iter::repeat_n(i as u8, value_len(i as u64) as usize))
+            .collect::<Vec<u8>>();
+
+        let mut buf = Vec::new();
+        write_combined_file(&table, &Vec::new(), &(), &data[..], data.len(), &mut buf).unwrap();
+        let view = IkvblobView::wrap(CountingMemory::new(buf)).await.unwrap();
+
+        let keys = [5u8, 250, 0, 99, 5, 17]
+            .map(|i| Multihash::<32>::wrap(2, [i; 32]))
+            .to_vec();
+        view.source_memory.reset();
+        let values = view.lookup_many(&keys).await.unwrap();
+        // The table and the value blob are small enough to be fetched with one read each
+        assert_eq!(view.source_memory.reads.get(), 2);
+        assert_eq!(view.source_memory.batches.get(), 2);
+
+        for (key, value) in keys.iter().zip(values) {
+            assert_eq!(value, view.lookup(key).await.unwrap());
+        }
+        assert_eq!(view.lookup_many(&[]).await.unwrap(), Vec::<Option<Vec<u8>>>::new());
+
+        // In a larger archive the buckets are far apart, but are still requested as one batch
+        let key = |i: u32| Multihash::<32>::wrap(2, i.to_le_bytes().repeat(8).try_into().unwrap());
+        let view = IkvblobView::<_, Multihash<32>, (u64, u64)>::wrap(CountingMemory::new(
+            build_test_file(5000),
+        ))
+        .await
+        .unwrap();
+        let keys = (0..50).map(|i| key(i * 97)).collect::<Vec<_>>();
+        view.source_memory.reset();
+        let values = view.lookup_many(&keys).await.unwrap();
+        assert_eq!(values[1], Some(vec![97]));
+        assert!(view.source_memory.reads.get() > 10);
+        assert_eq!(view.source_memory.batches.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_storage_errors() {
+        let key = |i: u32| Multihash::<32>::wrap(2, i.to_le_bytes().repeat(8).try_into().unwrap());
+        let view = IkvblobView::<_, Multihash<32>, (u64, u64)>::wrap(CountingMemory::new(
+            build_test_file(100),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(view.lookup(&key(1000)).await.unwrap(), None);
+
+        for strategy in [LookupStrategy::Concurrent, LookupStrategy::Sequential] {
+            let view = IkvblobView {
+                lookup_strategy: strategy,
+                ..IkvblobView::wrap(CountingMemory::new(build_test_file(100))).await.unwrap()
+            };
+            view.source_memory.fail_reads.set(true);
+            for k in [key(1), key(1000)] {
+                match view.lookup(&k).await {
+                    Err(IkvblobError::Storage(e)) => assert!(e.is_transient()),
+                    other => panic!("Expected a storage error, got {:?}", other),
+                }
+            }
+            assert!(matches!(
+                view.lookup_many(&[key(1)]).await,
+                Err(IkvblobError::Storage(_))
+            ));
+        }
+
+        let mem = CountingMemory::new(build_test_file(100));
+        mem.fail_reads.set(true);
+        let err = IkvblobView::<_, Multihash<32>, (u64, u64)>::wrap(mem).await.unwrap_err();
+        assert!(matches!(err, IkvblobError::Storage(_)));
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_files() {
+        type View = IkvblobView<'static, Vec<u8>, Multihash<32>, (u64, u64)>;
+        async fn wrap(buf: Vec<u8>) -> Result<View, IkvblobError> { IkvblobView::wrap(buf).await }
+        let file = build_test_file(100);
+        let header = IkvblobHeader::parse(&file).unwrap();
+        assert!(wrap(file.clone()).await.is_ok());
+
+        assert!(matches!(
+            wrap(file[..10].to_vec()).await,
+            Err(IkvblobError::Truncated { .. })
+        ));
+        assert!(matches!(
+            wrap(file[..file.len() - 1].to_vec()).await,
+            Err(IkvblobError::Truncated { .. })
+        ));
+        let mut longer = file.clone();
+        longer.push(0);
+        assert!(matches!(wrap(longer).await, Err(IkvblobError::LayoutInvariant(_))));
+
+        let mut bad_magic = file.clone();
+        bad_magic[0] = 1;
+        assert!(matches!(wrap(bad_magic).await, Err(IkvblobError::BadMagic)));
+
+        let mut bad_metadata = file.clone();
+        bad_metadata[header.dynamic_metadata_offset as usize] = 0xff;
+        assert!(matches!(
+            wrap(bad_metadata).await,
+            Err(IkvblobError::MetadataDecode(_))
+        ));
+
+        let metadata_with = |md: Value| {
+            let mut md_bytes = Vec::new();
+            ciborium::into_writer(&md, &mut md_bytes).unwrap();
+            let md_size = md_bytes.len() as u64;
+            md_bytes.resize((md_bytes.len() + 7) & !7, 0);
+            let shift = header.dynamic_metadata_offset + md_bytes.len() as u64
+                - header.cuckoo_table_offset;
+            let new_header = IkvblobHeader {
+                dynamic_metadata_size: md_size,
+                cuckoo_table_offset: header.cuckoo_table_offset + shift,
+                value_blob_offset: header.value_blob_offset + shift,
+                ..header.clone()
+            };
+            let mut new_file = Vec::new();
+            new_header.write(&mut new_file).unwrap();
+            new_file.extend_from_slice(&md_bytes);
+            new_file.extend_from_slice(&file[header.cuckoo_table_offset as usize..]);
+            new_file
+        };
+        let compression = |t: &str| {
+            Value::Map(vec![
+                ("compression_type".into(), t.into()),
+                ("compression_dict".into(), Value::Bytes(vec![])),
+            ])
+        };
+        assert!(matches!(
+            wrap(metadata_with(compression("lz4"))).await,
+            Err(IkvblobError::UnsupportedCompression(t)) if t == "lz4"
+        ));
+        let only_type = Value::Map(vec![("compression_type".into(), "zstd".into())]);
+        assert!(matches!(
+            wrap(metadata_with(only_type)).await,
+            Err(IkvblobError::MetadataDecode(_))
+        ));
+        // Before version 3, an index_partitions key doesn't split the index
+        let partitions = |p: Value| Value::Map(vec![("index_partitions".into(), p)]);
+        let uneven = (header.cuckoo_table_num_buckets() + 1) as u64;
+        for p in [uneven.into(), 0.into(), "4".into()] {
+            let view = wrap(metadata_with(partitions(p))).await.unwrap();
+            assert_eq!(view.index_partitions, 1);
+        }
+
+        // In version 3, its value is the last byte of the metadata
+        let entries = [(test_key(1), (0u64, 1u64))];
+        let table = StaticCuckooTable::<8, 2, _, _>::from_iter(entries.into_iter(), 1.2);
+        let mut writer = StreamingWriter::partitioned(Vec::new()).unwrap();
+        writer.push_value(&[1]).unwrap();
+        let partitioned = writer.finish(&table, &[], &()).unwrap();
+        let view = wrap(partitioned.clone()).await.unwrap();
+        assert_eq!(view.index_partitions, 1);
+        let header = &view.header;
+        let md_end = (header.dynamic_metadata_offset + header.dynamic_metadata_size) as usize;
+        let uneven = (2..24).find(|p| header.cuckoo_table_num_buckets() % p != 0).unwrap();
+        let with_partitions = |p: u8| {
+            let mut file = partitioned.clone();
+            file[md_end - 1] = p;
+            file
+        };
+        assert!(matches!(
+            wrap(with_partitions(uneven as u8)).await,
+            Err(IkvblobError::LayoutInvariant(_))
+        ));
+        // 0, and -1 in CBOR
+        for invalid in [0x00, 0x20] {
+            assert!(matches!(
+                wrap(with_partitions(invalid)).await,
+                Err(IkvblobError::MetadataDecode(_))
+            ));
+        }
+
+        // Point the first entry of the table somewhere past the end of the value blob
+        let mut bad_address = file.clone();
+        let table_start = header.cuckoo_table_offset as usize;
+        let entry = (table_start..file.len())
+            .step_by(header.cuckoo_entry_size as usize)
+            .find(|&e| file[e + 39] != 0)
+            .unwrap();
+        bad_address[entry + 40..entry + 48].copy_from_slice(&u64::MAX.to_le_bytes());
+        let key = Option::<(Multihash<32>, (u64, u64))>::read(&mut &bad_address[entry..])
+            .unwrap()
+            .unwrap()
+            .0;
+        let view = wrap(bad_address).await.unwrap();
+        assert!(matches!(
+            view.lookup(&key).await,
+            Err(IkvblobError::LayoutInvariant(_))
+        ));
+        assert!(matches!(
+            view.lookup_many(&[key]).await,
+            Err(IkvblobError::LayoutInvariant(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_verify_checksum() {
+        let file = build_test_file(100);
+        let view = IkvblobView::<_, Multihash<32>, (u64, u64)>::wrap(file.clone())
+            .await
+            .unwrap();
+        let mut reported = Vec::new();
+        view.verify(|done, total| reported.push((done, total))).await.unwrap();
+        assert_eq!(reported, vec![(file.len() - 4, file.len() - 4)]);
+
+        let mut reported = Vec::new();
+        verify_checksum(&file, 1000, |done, total| reported.push((done, total)))
+            .await
+            .unwrap();
+        assert_eq!(reported.len(), (file.len() - 4).div_ceil(1000));
+        assert!(reported.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(reported.last(), Some(&(file.len() - 4, file.len() - 4)));
+
+        // Flip a bit in the last value. Lookups of other keys still work, but verification fails.
+        let mut corrupt = file.clone();
+        let last = corrupt.len() - 5;
+        corrupt[last] ^= 1;
+        let view = IkvblobView::<_, Multihash<32>, (u64, u64)>::wrap(corrupt)
+            .await
+            .unwrap();
+        match view.verify(|_, _| {}).await {
+            Err(IkvblobError::ChecksumMismatch { expected, actual }) => {
+                assert_ne!(expected, actual)
+            }
+            other => panic!("Expected a checksum mismatch, got {:?}", other),
+        }
+
+        assert!(matches!(
+            verify_checksum(&vec![1u8, 2], 1000, |_, _| {}).await,
+            Err(IkvblobError::Truncated { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_scan() {
+        let test_size = 300u32;
+        let key = |i: u32| Multihash::<32>::wrap(2, i.to_le_bytes().repeat(8).try_into().unwrap());
+        let view = IkvblobView::<_, Multihash<32>, (u64, u64)>::wrap(CountingMemory::new(
+            build_test_file(test_size),
+        ))
+        .await
+        .unwrap();
+
+        let options = ScanOptions {
+            buckets_per_read: 7,
+            ..Default::default()
+        };
+        let entries = view.scan(&options).try_collect::<Vec<_>>().await.unwrap();
+        let mut values = entries
+            .iter()
+            .map(|e| (e.key, e.value.clone()))
+            .collect::<Vec<_>>();
+        values.sort_by_key(|(k, _)| *k.digest());
+        let mut expected = (0..test_size)
+            .map(|i| (key(i), vec![i as u8]))
+            .collect::<Vec<_>>();
+        expected.sort_by_key(|(k, _)| *k.digest());
+        assert_eq!(values, expected);
+        assert!(entries.windows(2).all(|w| w[0].bucket <= w[1].bucket));
+
+        let addresses = view
+            .scan_addresses(&options)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            addresses.iter().map(|e| (e.bucket, e.key)).collect::<Vec<_>>(),
+            entries.iter().map(|e| (e.bucket, e.key)).collect::<Vec<_>>()
+        );
+
+        // Make reads fail in the middle of the scan, then resume after the last entry received
+        let mut received = Vec::new();
+        {
+            let mut scan = std::pin::pin!(view.scan(&options));
+            while let Some(entry) = scan.next().await {
+                match entry {
+                    Ok(entry) => received.push(entry),
+                    Err(e) => {
+                        assert!(matches!(e, IkvblobError::Storage(_)));
+                        break;
+                    }
+                }
+                if received.len() == 100 {
+                    view.source_memory.fail_reads.set(true);
+                }
+            }
+            assert!(scan.next().await.is_none());
+        }
+        view.source_memory.fail_reads.set(false);
+        let resumed = ScanOptions {
+            start_bucket: received.last().unwrap().bucket + 1,
+            ..options.clone()
+        };
+        received.extend(view.scan(&resumed).try_collect::<Vec<_>>().await.unwrap());
+        assert_eq!(received, entries);
+    }
+
+    #[tokio::test]
+    async fn test_user_metadata_roundtrip() {
+        let kvs = (0..10u32).map(|i| (Multihash::<32>::wrap(2, [i as u8; 32]), (i as u64, 1u64)));
+        let table = StaticCuckooTable::<8, 2, _, _>::from_iter(kvs, 1.2);
+        let data = (0..10).collect::<Vec<u8>>();
+        let user_metadata = cbor!({
+            "description" => "digits",
+            "build_timestamp" => 1700000000,
+        })
+        .unwrap();
+
+        let mut buf = Vec::new();
+        write_combined_file(&table, &[], &user_metadata, &data[..], data.len(), &mut buf).unwrap();
+        let view = IkvblobView::<_, Multihash<32>, (u64, u64)>::wrap(buf).await.unwrap();
+        let md = view.metadata();
+        assert_eq!(md.user_keys().collect::<Vec<_>>(), vec!["build_timestamp", "description"]);
+        assert_eq!(md.get_as::<String>("description").unwrap().as_deref(), Some("digits"));
+        assert_eq!(md.get_as::<u64>("build_timestamp").unwrap(), Some(1700000000));
+
+        let reserved = cbor!({ "compression_type" => "none" }).unwrap();
+        let err = write_combined_file(&table, &[], &reserved, &data[..], data.len(), Vec::new())
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<IkvblobError>(),
+            Some(IkvblobError::ReservedMetadataKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_blocking_reader() {
+        let test_size = 500u32;
+        let key = |i: u32| Multihash::<32>::wrap(2, i.to_le_bytes().repeat(8).try_into().unwrap());
+        let file = build_test_file(test_size);
+
+        let tmp_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(tmp_file.path(), &file).unwrap();
+        let fs_file = std::fs::File::open(tmp_file.path()).unwrap();
+        let mmap = crate::memory_view::MmapMemory {
+            mmap: unsafe { memmap2::Mmap::map(&fs_file).unwrap() },
+        };
+
+        fn check<M: SyncMemory>(memory: M, test_size: u32, key: impl Fn(u32) -> Multihash<32>) {
+            let view = IkvblobView::<_, Multihash<32>, (u64, u64)>::wrap_blocking(memory).unwrap();
+            for i in 0..test_size {
+                assert_eq!(view.lookup_blocking(&key(i)).unwrap(), Some(vec![i as u8]));
+            }
+            assert_eq!(view.lookup_blocking(&key(test_size)).unwrap(), None);
+            let mut reported = Vec::new();
+            view.verify_blocking(|done, total| reported.push((done, total)))
+                .unwrap();
+            assert_eq!(reported.last().map(|(done, total)| done == total), Some(true));
+        }
+        check(file.clone(), test_size, key);
+        check(mmap, test_size, key);
+        check(fs_file, test_size, key);
+
+        // Errors are the same as on the async path
+        assert!(matches!(
+            IkvblobView::<_, Multihash<32>, (u64, u64)>::wrap_blocking(file[..100].to_vec()),
+            Err(IkvblobError::Truncated { .. })
+        ));
+        let mut corrupt = file.clone();
+        let last = corrupt.len() - 5;
+        corrupt[last] ^= 1;
+        assert!(matches!(
+            verify_checksum_blocking(&corrupt, 1000, |_, _| {}),
+            Err(IkvblobError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_compressed_values() {
+        let key = |i: u32| Multihash::<32>::wrap(2, i.to_le_bytes().repeat(8).try_into().unwrap());
+        // Any bytes can serve as a raw content dictionary
+        let dict = b"ikvblob test dictionary, ikvblob test dictionary".repeat(20);
+        let encoder_dict = zstd::dict::EncoderDictionary::copy(&dict, 3);
+        let mut compressor = crate::construction::build_compressor(&encoder_dict);
+
+        let big_value = (0..3 * 1024 * 1024u32)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let small_value = b"small value".to_vec();
+        // A frame without the content size, as written by a streaming compressor
+        let mut encoder = zstd::stream::write::Encoder::with_dictionary(Vec::new(), 3, &dict)
+            .unwrap();
+        encoder.include_contentsize(false).unwrap();
+        std::io::Write::write_all(&mut encoder, &big_value).unwrap();
+        let values = [
+            compressor.compress(&small_value).unwrap(),
+            compressor.compress(&big_value).unwrap(),
+            encoder.finish().unwrap(),
+        ];
+        assert!(matches!(zstd::zstd_safe::get_frame_content_size(&values[2]), Ok(None)));
+
+        let mut data = Vec::new();
+        let mut kvs = Vec::new();
+        for (i, v) in values.iter().enumerate() {
+            kvs.push((key(i as u32), (data.len() as u64, v.len() as u64)));
+            data.extend_from_slice(v);
+        }
+        let table = StaticCuckooTable::<8, 2, _, _>::from_iter(kvs.into_iter(), 1.2);
+        let mut buf = Vec::new();
+        write_combined_file(&table, &dict, &(), &data[..], data.len(), &mut buf).unwrap();
+
+        let view = IkvblobView::<_, Multihash<32>, (u64, u64)>::wrap(buf)
+            .await
+            .unwrap();
+        assert_eq!(view.lookup(&key(0)).await.unwrap(), Some(small_value));
+        assert_eq!(view.lookup(&key(1)).await.unwrap().as_ref(), Some(&big_value));
+        assert_eq!(view.lookup(&key(2)).await.unwrap().as_ref(), Some(&big_value));
+        assert!(matches!(
+            view.lookup_ref(&key(1)).unwrap(),
+            Some(Cow::Owned(v)) if v == big_value
+        ));
+
+        let view = view.with_max_value_size(1024 * 1024);
+        assert_eq!(view.lookup(&key(0)).await.unwrap(), Some(b"small value".to_vec()));
+        assert!(matches!(
+            view.lookup(&key(1)).await,
+            Err(IkvblobError::ValueTooLarge { size: Some(size), limit: 1048576 })
+                if size == big_value.len() as u64
+        ));
+        assert!(matches!(
+            view.lookup(&key(2)).await,
+            Err(IkvblobError::ValueTooLarge { size: None, limit: 1048576 })
+        ));
+    }
+
+    #[test]
+    fn test_lookup_ref() {
+        let test_size = 500u32;
+        let key = |i: u32| Multihash::<32>::wrap(2, i.to_le_bytes().repeat(8).try_into().unwrap());
+        let file = build_test_file(test_size);
+        let mmap_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(mmap_file.path(), &file).unwrap();
+        let mmap = crate::memory_view::MmapMemory {
+            mmap: unsafe { memmap2::Mmap::map(mmap_file.as_file()).unwrap() },
+        };
+
+        fn check<M: SyncMemory + SliceMemory>(
+            memory: M,
+            test_size: u32,
+            key: impl Fn(u32) -> Multihash<32>,
+        ) {
+            let view = IkvblobView::<_, Multihash<32>, (u64, u64)>::wrap_blocking(memory).unwrap();
+            let bytes = view.source_memory.as_bytes().as_ptr_range();
+            for i in 0..test_size {
+                match view.lookup_ref(&key(i)).unwrap() {
+                    // The value points into the memory itself
+                    Some(Cow::Borrowed(value)) => {
+                        assert_eq!(value, [i as u8]);
+                        assert!(bytes.contains(&value.as_ptr()));
+                    }
+                    other => panic!("Expected a borrowed value, got {:?}", other),
+                }
+            }
+            assert_eq!(view.lookup_ref(&key(test_size)).unwrap(), None);
+        }
+        check(file, test_size, key);
+        check(mmap, test_size, key);
+    }
 }
@@ -1,13 +1,19 @@
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use ciborium::cbor;
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 // use multihash::Multihash;
+use serde::Serialize;
 use std::{
     array,
     error::Error,
     io::{self, Write},
 };
 
-use crate::{cuckoo::StaticCuckooTable, multihash::Multihash, utils::CRC32Writer};
+use crate::{
+    cuckoo::CuckooIndex,
+    error::IkvblobError,
+    metadata::{DynamicMetadata, COMPRESSION_DICT_KEY, COMPRESSION_TYPE_KEY, INDEX_PARTITIONS_KEY},
+    multihash::Multihash,
+    utils::CRC32Writer,
+};
 
 // TODO document
 pub trait StaticSizeSerializable: Sized {
@@ -28,7 +34,31 @@ pub trait StaticSizeSerializable: Sized {
     }
 }
 
-const FILE_FORMAT_VERSION: u64 = 1;
+/// Version of archives written by `write_combined_file`, which start with their header.
+pub const FILE_FORMAT_VERSION: u64 = 1;
+
+/// Version of archives written by `StreamingWriter`. They start with the values, and their
+/// header is a footer in front of the checksum, see the README.
+pub const FOOTER_FORMAT_VERSION: u64 = 2;
+
+/// Version of archives with a partitioned index, see `cuckoo::region_of`. They have the footer
+/// layout of version 2 and the number of regions in the `index_partitions` metadata key, and
+/// readers that don't split the index reject them by their version.
+pub const PARTITIONED_FORMAT_VERSION: u64 = 3;
+
+/// Newest format version this library reads.
+pub const MAX_SUPPORTED_VERSION: u64 = PARTITIONED_FORMAT_VERSION;
+
+/// Size of the start of archives in the footer layout: the magic bytes and the version, so that
+/// readers can tell the layouts apart.
+pub const FOOTER_LAYOUT_PREFIX_SIZE: usize = MAGIC.len() + std::mem::size_of::<u64>();
+
+/// Upper bound on `cuckoo_table_num_hashers` accepted when reading a header, so that a corrupt
+/// header can't make the reader allocate an absurd number of hashers.
+pub const MAX_NUM_HASHERS: u64 = 64;
+
+/// Size of the CRC32 checksum at the end of the file.
+pub const CHECKSUM_SIZE: usize = std::mem::size_of::<u32>();
 
 const MAGIC: &[u8; 8] = b"\0Ikvblob";
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -50,32 +80,161 @@ pub struct IkvblobHeader {
 
 impl IkvblobHeader {
     pub fn cuckoo_table_num_buckets(&self) -> usize {
-        (self.cuckoo_table_size / (self.cuckoo_table_elems_per_bucket * self.cuckoo_entry_size))
-            as usize
+        self.cuckoo_table_elems_per_bucket
+            .checked_mul(self.cuckoo_entry_size)
+            .and_then(|bucket_size| self.cuckoo_table_size.checked_div(bucket_size))
+            .unwrap_or(0) as usize
+    }
+
+    /// Whether the header is a footer after the sections rather than at the start of the file.
+    pub fn is_footer(&self) -> bool { self.version >= FOOTER_FORMAT_VERSION }
+
+    /// Offset of the first byte after the header, or after the prefix of the footer layout.
+    fn sections_start(&self) -> u64 {
+        if self.is_footer() {
+            FOOTER_LAYOUT_PREFIX_SIZE as u64
+        } else {
+            Self::SER_SIZE as u64
+        }
+    }
+
+    fn section_ranges(&self) -> [(&'static str, u64, u64); 3] {
+        [
+            ("dynamic metadata", self.dynamic_metadata_offset, self.dynamic_metadata_size),
+            ("cuckoo table", self.cuckoo_table_offset, self.cuckoo_table_size),
+            ("value blob", self.value_blob_offset, self.value_blob_size),
+        ]
     }
 
     pub fn total_size(&self) -> usize {
-        (self.value_blob_offset + self.value_blob_size + 4) as usize
+        let sections_end = self
+            .section_ranges()
+            .iter()
+            .map(|(_, offset, size)| offset.saturating_add(*size))
+            .max()
+            .unwrap_or(0);
+        let footer_size = if self.is_footer() { Self::SER_SIZE } else { 0 };
+        (sections_end as usize)
+            .saturating_add(footer_size)
+            .saturating_add(CHECKSUM_SIZE)
     }
 
-    pub fn invariant_check(&self) {
-        let md_range = self.dynamic_metadata_offset
-            ..(self.dynamic_metadata_offset + self.dynamic_metadata_size);
-        let ct_range =
-            self.cuckoo_table_offset..(self.cuckoo_table_offset + self.cuckoo_table_size);
-        let vb_range = self.value_blob_offset..(self.value_blob_offset + self.value_blob_size);
+    pub fn invariant_check(&self) -> Result<(), IkvblobError> {
+        let invariant_error = |e: String| Err(IkvblobError::LayoutInvariant(e));
+
+        if self.cuckoo_entry_size == 0 || self.cuckoo_table_elems_per_bucket == 0 {
+            return invariant_error("cuckoo table entries and buckets must be non-empty".into());
+        }
+        if self.cuckoo_table_num_hashers == 0 || self.cuckoo_table_num_hashers > MAX_NUM_HASHERS {
+            return invariant_error(format!(
+                "number of hashers must be between 1 and {}, got {}",
+                MAX_NUM_HASHERS, self.cuckoo_table_num_hashers
+            ));
+        }
+        match self
+            .cuckoo_entry_size
+            .checked_mul(self.cuckoo_table_elems_per_bucket)
+        {
+            Some(bucket_size) if self.cuckoo_table_size.is_multiple_of(bucket_size) => {}
+            _ => {
+                return invariant_error(
+                    "cuckoo table size is not a multiple of the bucket size".into(),
+                )
+            }
+        }
+
+        let mut ranges = Vec::new();
+        for (name, offset, size) in self.section_ranges() {
+            if offset < self.sections_start() {
+                return invariant_error(format!("{} overlaps the header", name));
+            }
+            match offset.checked_add(size) {
+                Some(end) if end <= usize::MAX as u64 - CHECKSUM_SIZE as u64 => {
+                    ranges.push((name, offset..end))
+                }
+                _ => return invariant_error(format!("{} is too large", name)),
+            }
+        }
 
         // assert that the ranges don't overlap
-        for a in [&md_range, &ct_range, &vb_range].into_iter() {
-            for b in [&md_range, &ct_range, &vb_range].into_iter() {
-                if a != b {
-                    assert!(
-                        a.end <= b.start || b.end <= a.start,
-                        "Invariant error: ranges overlap"
-                    );
+        for (a_name, a) in ranges.iter() {
+            for (b_name, b) in ranges.iter() {
+                if a_name != b_name && !(a.end <= b.start || b.end <= a.start) {
+                    return invariant_error(format!("{} and {} overlap", a_name, b_name));
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Parses and validates a header from the first `SER_SIZE` bytes of an archive.
+    pub fn parse(bytes: &[u8]) -> Result<Self, IkvblobError> {
+        if bytes.len() < Self::SER_SIZE {
+            return Err(IkvblobError::Truncated {
+                expected: Self::SER_SIZE as u64,
+                actual: bytes.len() as u64,
+            });
+        }
+        if bytes[..MAGIC.len()] != *MAGIC {
+            return Err(IkvblobError::BadMagic);
+        }
+        let mut fields = bytes[MAGIC.len()..Self::SER_SIZE]
+            .chunks_exact(std::mem::size_of::<u64>())
+            .map(LittleEndian::read_u64);
+        let mut next = || fields.next().unwrap_or_default();
+
+        let version = next();
+        if version > MAX_SUPPORTED_VERSION {
+            return Err(IkvblobError::UnsupportedVersion {
+                found: version,
+                supported: MAX_SUPPORTED_VERSION,
+            });
+        }
+
+        let res = IkvblobHeader {
+            version,
+            dynamic_metadata_offset: next(),
+            dynamic_metadata_size: next(),
+            cuckoo_table_offset: next(),
+            cuckoo_table_size: next(),
+            cuckoo_entry_size: next(),
+            cuckoo_table_elems_per_bucket: next(),
+            cuckoo_table_num_hashers: next(),
+            value_blob_offset: next(),
+            value_blob_size: next(),
+        };
+        res.invariant_check()?;
+        Ok(res)
+    }
+}
+
+impl IkvblobHeader {
+    /// Parses and validates the header of an archive in either layout, given its first
+    /// `SER_SIZE` bytes and the `SER_SIZE` bytes in front of its checksum. The start tells the
+    /// version, and with it whether the header is at the start or in the footer.
+    pub fn parse_with_footer(start: &[u8], footer: &[u8]) -> Result<Self, IkvblobError> {
+        let prefix = start
+            .get(..FOOTER_LAYOUT_PREFIX_SIZE)
+            .ok_or(IkvblobError::Truncated {
+                expected: FOOTER_LAYOUT_PREFIX_SIZE as u64,
+                actual: start.len() as u64,
+            })?;
+        if prefix[..MAGIC.len()] != *MAGIC {
+            return Err(IkvblobError::BadMagic);
+        }
+        let version = LittleEndian::read_u64(&prefix[MAGIC.len()..]);
+        if version < FOOTER_FORMAT_VERSION {
+            return Self::parse(start);
+        }
+
+        let header = Self::parse(footer)?;
+        if header.version != version {
+            return Err(IkvblobError::LayoutInvariant(format!(
+                "File starts with version {}, but its footer has version {}",
+                version, header.version
+            )));
+        }
+        Ok(header)
     }
 }
 
@@ -85,7 +244,7 @@ impl StaticSizeSerializable for IkvblobHeader {
     where
         W: io::Write,
     {
-        self.invariant_check();
+        self.invariant_check()?;
         write.write_all(MAGIC)?;
         write.write_u64::<LittleEndian>(self.version)?;
         write.write_u64::<LittleEndian>(self.dynamic_metadata_offset as u64)?;
@@ -106,47 +265,9 @@ impl StaticSizeSerializable for IkvblobHeader {
     where
         R: io::Read,
     {
-        let mut magic = [0u8; 8];
-        read.read_exact(&mut magic)?;
-        if magic != *MAGIC {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Invalid magic number in header",
-            ));
-        }
-        let version = read.read_u64::<LittleEndian>()?;
-
-        if version > FILE_FORMAT_VERSION {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Unsupported IkvBlob format version: file has version {}, library supports versions <= {}", version, FILE_FORMAT_VERSION),
-            ));
-        }
-
-        let dynamic_metadata_offset = read.read_u64::<LittleEndian>()?;
-        let dynamic_metadata_size = read.read_u64::<LittleEndian>()?;
-        let cuckoo_table_offset = read.read_u64::<LittleEndian>()?;
-        let cuckoo_table_size = read.read_u64::<LittleEndian>()?;
-        let cuckoo_entry_size = read.read_u64::<LittleEndian>()?;
-        let cuckoo_table_num_buckets = read.read_u64::<LittleEndian>()?;
-        let cuckoo_table_num_hashers = read.read_u64::<LittleEndian>()?;
-        let value_blob_offset = read.read_u64::<LittleEndian>()?;
-        let value_blob_size = read.read_u64::<LittleEndian>()?;
-
-        let res = IkvblobHeader {
-            version,
-            dynamic_metadata_offset,
-            dynamic_metadata_size,
-            cuckoo_table_offset,
-            cuckoo_table_size,
-            cuckoo_entry_size,
-            cuckoo_table_elems_per_bucket: cuckoo_table_num_buckets,
-            cuckoo_table_num_hashers,
-            value_blob_offset,
-            value_blob_size,
-        };
-        res.invariant_check();
-        Ok(res)
+        let mut buf = [0u8; Self::SER_SIZE];
+        read.read_exact(&mut buf)?;
+        Ok(Self::parse(&buf)?)
     }
 
     const SER_SIZE: usize = MAGIC.len() + 10 * std::mem::size_of::<u64>();
@@ -281,9 +402,57 @@ impl StaticSizeSerializable for IndexTableEntry {
     const SER_SIZE: usize = 32 + 2 * std::mem::size_of::<u64>();
 }
 
-pub fn write_combined_file<const BS: usize, const HS: usize, K, V, R: io::Read, W: io::Write>(
-    map_table: &StaticCuckooTable<BS, HS, K, V>,
+/// Encodes the dynamic metadata section, padded to a multiple of 8 bytes. Also returns its size
+/// without the padding. `index_partitions` is only stored in archives of version 3.
+fn encode_metadata<MD: Serialize + ?Sized>(
+    compression_dict: &[u8],
+    index_partitions: Option<usize>,
+    user_metadata: &MD,
+) -> Result<(Vec<u8>, u64), IkvblobError> {
+    let mut md = DynamicMetadata::from_user(user_metadata)?;
+    if let Some(partitions) = index_partitions {
+        md.set_reserved(INDEX_PARTITIONS_KEY, (partitions as u64).into());
+    }
+    if !compression_dict.is_empty() {
+        md.set_reserved(COMPRESSION_TYPE_KEY, "zstd".into());
+        md.set_reserved(
+            COMPRESSION_DICT_KEY,
+            ciborium::Value::Bytes(compression_dict.to_vec()),
+        );
+    }
+
+    let mut md_bytes = md.encode()?;
+    let md_size = md_bytes.len() as u64;
+    md_bytes.resize(align8(md_bytes.len()), 0);
+    Ok((md_bytes, md_size))
+}
+
+fn align8(n: usize) -> usize { (n + 7) & !7 }
+
+fn partitioned_version_error() -> Box<dyn Error> {
+    let message = "A partitioned index needs format version 3, see `StreamingWriter::partitioned`";
+    io::Error::new(io::ErrorKind::InvalidInput, message).into()
+}
+
+/// Writes a complete archive. `user_metadata` is merged into the dynamic metadata section; it
+/// must serialize to a map with string keys that doesn't touch reserved keys (see
+/// `metadata::RESERVED_KEYS`). Pass `&()` to write no user metadata.
+///
+/// The index is a `StaticCuckooTable` or similar. A partitioned index can only be written by a
+/// `StreamingWriter::partitioned`.
+pub fn write_combined_file<
+    const BS: usize,
+    const HS: usize,
+    K,
+    V,
+    T: CuckooIndex<BS, HS, K, V> + ?Sized,
+    MD: Serialize + ?Sized,
+    R: io::Read,
+    W: io::Write,
+>(
+    map_table: &T,
     compression_dict: &[u8],
+    user_metadata: &MD,
     mut result_read: R,
     result_byte_len: usize,
     mut base_desination: W,
@@ -292,25 +461,16 @@ where
     K: Eq + Copy + StaticSizeSerializable,
     Option<(K, V)>: StaticSizeSerializable,
 {
+    if map_table.partitions() > 1 {
+        return Err(partitioned_version_error());
+    }
     let mut dest = CRC32Writer::new(&mut base_desination);
 
     let cuckoo_entry_size = Option::<(K, V)>::SER_SIZE as u64;
-    let cuckoo_table_size = (map_table.table.len() * Option::<(K, V)>::SER_SIZE * BS) as u64;
+    let cuckoo_table_size = (map_table.num_buckets() * Option::<(K, V)>::SER_SIZE * BS) as u64;
 
-    let md = if compression_dict.len() > 0 {
-        cbor!({
-            "compression_type" => "zstd",
-            "compression_dict" => ciborium::Value::Bytes(compression_dict.to_vec())
-        })
-    } else {
-        cbor!({})
-    };
-
-    let mut md_bytes = Vec::<u8>::new();
-    ciborium::into_writer(&md?, &mut md_bytes)?;
-    let md_size = md_bytes.len() as u64;
-    let aligned_md_size = (md_bytes.len() + 7) & !7;
-    md_bytes.resize(aligned_md_size, 0);
+    let (md_bytes, md_size) = encode_metadata(compression_dict, None, user_metadata)?;
+    let aligned_md_size = md_bytes.len();
 
     let header = IkvblobHeader {
         version: FILE_FORMAT_VERSION,
@@ -329,9 +489,7 @@ where
 
     header.write(&mut dest)?;
     dest.write_all(&md_bytes)?;
-    for bucket in map_table.table.iter() {
-        bucket.write(&mut dest)?;
-    }
+    map_table.try_for_each_bucket(&mut |bucket| bucket.write(&mut dest))?;
 
     io::copy(&mut result_read, &mut dest)?;
 
@@ -342,6 +500,94 @@ where
     Ok(())
 }
 
+/// Writes an archive in a single pass, in the footer layout of format version 2: the values
+/// come first, as they are pushed, followed by the metadata, the index and the header as a
+/// footer. Unlike `write_combined_file`, this needs neither the index nor the size of the values
+/// up front, so the archive can be written to a pipe or a multipart upload, and the values don't
+/// have to be kept anywhere until the index is done.
+pub struct StreamingWriter<W: io::Write> {
+    dest: CRC32Writer<W>,
+    version: u64,
+    values_size: u64,
+}
+
+impl<W: io::Write> StreamingWriter<W> {
+    pub fn new(dest: W) -> io::Result<Self> { Self::with_version(dest, FOOTER_FORMAT_VERSION) }
+
+    /// Like `new`, but writes format version 3, which a partitioned index needs.
+    pub fn partitioned(dest: W) -> io::Result<Self> {
+        Self::with_version(dest, PARTITIONED_FORMAT_VERSION)
+    }
+
+    fn with_version(dest: W, version: u64) -> io::Result<Self> {
+        let mut dest = CRC32Writer::new(dest);
+        dest.write_all(MAGIC)?;
+        dest.write_u64::<LittleEndian>(version)?;
+        Ok(StreamingWriter {
+            dest,
+            version,
+            values_size: 0,
+        })
+    }
+
+    /// Appends a value and returns its address, to be put into the index.
+    pub fn push_value(&mut self, value: &[u8]) -> io::Result<(u64, u64)> {
+        self.dest.write_all(value)?;
+        let address = (self.values_size, value.len() as u64);
+        self.values_size += value.len() as u64;
+        Ok(address)
+    }
+
+    /// Writes the metadata, the index and the footer, and returns the destination. See
+    /// `write_combined_file` for `user_metadata`.
+    pub fn finish<const BS: usize, const HS: usize, K, V, T, MD>(
+        mut self,
+        map_table: &T,
+        compression_dict: &[u8],
+        user_metadata: &MD,
+    ) -> Result<W, Box<dyn Error>>
+    where
+        K: Eq + Copy + StaticSizeSerializable,
+        Option<(K, V)>: StaticSizeSerializable,
+        T: CuckooIndex<BS, HS, K, V> + ?Sized,
+        MD: Serialize + ?Sized,
+    {
+        let partitioned = self.version == PARTITIONED_FORMAT_VERSION;
+        if map_table.partitions() > 1 && !partitioned {
+            return Err(partitioned_version_error());
+        }
+        let values_end = FOOTER_LAYOUT_PREFIX_SIZE + self.values_size as usize;
+        let padding = align8(values_end) - values_end;
+        self.dest.write_all(&[0; 8][..padding])?;
+
+        let index_partitions = partitioned.then(|| map_table.partitions());
+        let (md_bytes, md_size) =
+            encode_metadata(compression_dict, index_partitions, user_metadata)?;
+        let cuckoo_table_size = (map_table.num_buckets() * Option::<(K, V)>::SER_SIZE * BS) as u64;
+        let header = IkvblobHeader {
+            version: self.version,
+            dynamic_metadata_offset: align8(values_end) as u64,
+            dynamic_metadata_size: md_size,
+            cuckoo_table_offset: (align8(values_end) + md_bytes.len()) as u64,
+            cuckoo_table_size,
+            cuckoo_entry_size: Option::<(K, V)>::SER_SIZE as u64,
+            cuckoo_table_elems_per_bucket: BS as u64,
+            cuckoo_table_num_hashers: HS as u64,
+            value_blob_offset: FOOTER_LAYOUT_PREFIX_SIZE as u64,
+            value_blob_size: self.values_size,
+        };
+
+        self.dest.write_all(&md_bytes)?;
+        let dest = &mut self.dest;
+        map_table.try_for_each_bucket(&mut |bucket| bucket.write(dest))?;
+        header.write(&mut self.dest)?;
+
+        let cs = self.dest.current_crc();
+        self.dest.write_u32::<LittleEndian>(cs)?;
+        Ok(self.dest.into_inner())
+    }
+}
+
 // Tests
 #[cfg(test)]
 mod tests {
@@ -350,23 +596,114 @@ mod tests {
 
     #[test]
     fn test_header_ser_deser() {
-        let header = IkvblobHeader {
+        let header = test_header();
+
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        let header2 = IkvblobHeader::read(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(header, header2);
+    }
+
+    fn test_header() -> IkvblobHeader {
+        IkvblobHeader {
             version: 1,
-            dynamic_metadata_offset: 2,
+            dynamic_metadata_offset: 88,
             dynamic_metadata_size: 3,
-            cuckoo_table_offset: 4,
-            cuckoo_table_size: 5,
+            cuckoo_table_offset: 96,
+            cuckoo_table_size: 84,
             cuckoo_entry_size: 6,
             cuckoo_table_elems_per_bucket: 7,
-            cuckoo_table_num_hashers: 8,
-            value_blob_offset: 9,
+            cuckoo_table_num_hashers: 2,
+            value_blob_offset: 180,
             value_blob_size: 10,
-        };
+        }
+    }
 
+    #[test]
+    fn test_header_errors() {
         let mut buf = Vec::new();
-        header.write(&mut buf).unwrap();
-        let header2 = IkvblobHeader::read(&mut Cursor::new(&buf)).unwrap();
-        assert_eq!(header, header2);
+        test_header().write(&mut buf).unwrap();
+
+        assert!(matches!(
+            IkvblobHeader::parse(&buf[..40]),
+            Err(IkvblobError::Truncated { expected: 88, actual: 40 })
+        ));
+
+        let mut bad_magic = buf.clone();
+        bad_magic[1] = b'X';
+        assert!(matches!(IkvblobHeader::parse(&bad_magic), Err(IkvblobError::BadMagic)));
+
+        let mut new_version = buf.clone();
+        new_version[8] = 4;
+        assert!(matches!(
+            IkvblobHeader::parse(&new_version),
+            Err(IkvblobError::UnsupportedVersion { found: 4, supported: 3 })
+        ));
+
+        let invalid_headers = [
+            IkvblobHeader { cuckoo_table_offset: 90, ..test_header() },
+            IkvblobHeader { dynamic_metadata_offset: 0, ..test_header() },
+            IkvblobHeader { cuckoo_entry_size: 0, ..test_header() },
+            IkvblobHeader { cuckoo_table_size: 85, ..test_header() },
+            IkvblobHeader { cuckoo_table_num_hashers: 0, ..test_header() },
+            IkvblobHeader { cuckoo_table_num_hashers: u64::MAX, ..test_header() },
+            IkvblobHeader { value_blob_size: u64::MAX, ..test_header() },
+            IkvblobHeader { cuckoo_entry_size: u64::MAX, ..test_header() },
+        ];
+        for header in invalid_headers {
+            assert!(matches!(header.invariant_check(), Err(IkvblobError::LayoutInvariant(_))));
+            // Writing an invalid header must fail too
+            assert!(header.write(&mut Vec::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_footer_header() {
+        let footer_header = IkvblobHeader {
+            version: FOOTER_FORMAT_VERSION,
+            value_blob_offset: FOOTER_LAYOUT_PREFIX_SIZE as u64,
+            value_blob_size: 10,
+            dynamic_metadata_offset: 32,
+            dynamic_metadata_size: 3,
+            cuckoo_table_offset: 40,
+            cuckoo_table_size: 84,
+            ..test_header()
+        };
+        let mut footer = Vec::new();
+        footer_header.write(&mut footer).unwrap();
+        assert_eq!(footer_header.total_size(), 124 + IkvblobHeader::SER_SIZE + CHECKSUM_SIZE);
+        let start = &footer[..FOOTER_LAYOUT_PREFIX_SIZE];
+        assert_eq!(IkvblobHeader::parse_with_footer(start, &footer).unwrap(), footer_header);
+
+        // Files of version 1 are parsed from their start, whatever the footer is
+        let mut header = Vec::new();
+        test_header().write(&mut header).unwrap();
+        let parsed = IkvblobHeader::parse_with_footer(&header, &[]).unwrap();
+        assert_eq!(parsed, test_header());
+
+        assert!(matches!(
+            IkvblobHeader::parse_with_footer(&footer[..4], &footer),
+            Err(IkvblobError::Truncated { .. })
+        ));
+        let mut other_version = footer.clone();
+        other_version[8] = 4;
+        assert!(matches!(
+            IkvblobHeader::parse_with_footer(start, &other_version),
+            Err(IkvblobError::UnsupportedVersion { found: 4, supported: 3 })
+        ));
+        let mut bad_magic = footer.clone();
+        bad_magic[1] = b'X';
+        assert!(matches!(
+            IkvblobHeader::parse_with_footer(start, &bad_magic),
+            Err(IkvblobError::BadMagic)
+        ));
+        // The footer must agree with the start of the file
+        let mut other_start = start.to_vec();
+        other_start[8] = 1;
+        assert!(IkvblobHeader::parse_with_footer(&other_start, &footer).is_err());
+        // The sections can't overlap the prefix
+        let overlapping = IkvblobHeader { value_blob_offset: 8, ..footer_header };
+        assert!(matches!(overlapping.invariant_check(), Err(IkvblobError::LayoutInvariant(_))));
     }
 
     #[test]
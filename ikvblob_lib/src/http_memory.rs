@@ -0,0 +1,310 @@
+//! HTTP backend
+//!
+//! `HttpMemory` reads an archive from any HTTP server that supports range requests, e.g. a
+//! static file server, a CDN or a public S3 bucket. Reads are `GET` requests with a `Range`
+//! header, and the length of the archive comes from the `Content-Length` of a `HEAD` request.
+
+use std::{ops::Range, sync::OnceLock};
+
+use reqwest::{
+    header::{HeaderMap, CONTENT_LENGTH, CONTENT_RANGE, RANGE},
+    Client, StatusCode,
+};
+
+use crate::memory_view::{Memory, MemoryError};
+
+/// An archive served over HTTP, see the module documentation.
+///
+/// Servers that answer a range request with the whole file instead of the requested range are
+/// rejected with a non-transient `MemoryError::Backend`, rather than silently downloading the
+/// whole archive on every read.
+#[derive(Debug, Clone)]
+pub struct HttpMemory {
+    client: Client,
+    url: String,
+    headers: HeaderMap,
+    len: OnceLock<usize>,
+}
+
+fn backend_error(message: String, transient: bool) -> MemoryError {
+    MemoryError::Backend { message, transient }
+}
+
+fn request_error(e: reqwest::Error) -> MemoryError {
+    let transient = e.is_timeout() || e.is_connect() || e.is_body();
+    backend_error(format!("HTTP request failed: {}", e), transient)
+}
+
+fn status_error(url: &str, status: StatusCode) -> MemoryError {
+    let transient = status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT;
+    backend_error(format!("HTTP request to {} failed with status {}", url, status), transient)
+}
+
+fn content_length(headers: &HeaderMap) -> Option<usize> {
+    headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+impl HttpMemory {
+    pub fn new(url: impl Into<String>) -> Self { Self::with_client(Client::new(), url) }
+
+    /// Uses the given client, e.g. to set timeouts or a proxy.
+    pub fn with_client(client: Client, url: impl Into<String>) -> Self {
+        HttpMemory {
+            client,
+            url: url.into(),
+            headers: HeaderMap::new(),
+            len: OnceLock::new(),
+        }
+    }
+
+    /// Sends these headers with every request, e.g. for authorization.
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    pub fn url(&self) -> &str { &self.url }
+}
+
+impl Memory for HttpMemory {
+    async fn read_slice(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        if range.start >= range.end {
+            // A range header can't ask for nothing
+            let len = self.len().await?;
+            if range.start > range.end || range.end > len {
+                return Err(MemoryError::OutOfBounds { range, len });
+            }
+            return Ok(Vec::new());
+        }
+
+        let response = self
+            .client
+            .get(&self.url)
+            .headers(self.headers.clone())
+            .header(RANGE, format!("bytes={}-{}", range.start, range.end - 1))
+            .send()
+            .await
+            .map_err(request_error)?;
+
+        match response.status() {
+            // The server may cut off a range that extends past the end of the file, which
+            // `read_exact` then reports as a short read
+            StatusCode::PARTIAL_CONTENT => {
+                let expected = format!("bytes {}-", range.start);
+                let content_range = response.headers().get(CONTENT_RANGE);
+                if !content_range
+                    .and_then(|v| v.to_str().ok())
+                    .is_some_and(|v| v.starts_with(&expected))
+                {
+                    return Err(backend_error(
+                        format!(
+                            "Server sent Content-Range {:?} for a request of {:?}",
+                            content_range, range
+                        ),
+                        false,
+                    ));
+                }
+            }
+            // A server may answer a request of the whole file with the whole file. Anything
+            // else means that the server doesn't support ranges.
+            StatusCode::OK
+                if range.start == 0 && content_length(response.headers()) == Some(range.end) => {}
+            StatusCode::OK => {
+                return Err(backend_error(
+                    format!(
+                        "Server of {} ignored the Range header, range requests are required",
+                        self.url
+                    ),
+                    false,
+                ))
+            }
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                return Err(MemoryError::OutOfBounds {
+                    range,
+                    len: self.len().await?,
+                })
+            }
+            status => return Err(status_error(&self.url, status)),
+        }
+
+        Ok(response.bytes().await.map_err(request_error)?.to_vec())
+    }
+
+    async fn len(&self) -> Result<usize, MemoryError> {
+        if let Some(len) = self.len.get() {
+            return Ok(*len);
+        }
+        let response = self
+            .client
+            .head(&self.url)
+            .headers(self.headers.clone())
+            .send()
+            .await
+            .map_err(request_error)?;
+        if !response.status().is_success() {
+            return Err(status_error(&self.url, response.status()));
+        }
+        let len = content_length(response.headers()).ok_or_else(|| {
+            backend_error(format!("Server of {} sent no Content-Length", self.url), false)
+        })?;
+        Ok(*self.len.get_or_init(|| len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+    };
+
+    use reqwest::header::{HeaderValue, AUTHORIZATION};
+
+    use super::*;
+    use crate::fileformat_read::{
+        tests::{build_test_file, test_key},
+        IkvblobView,
+    };
+
+    /// Serves `data` at every path over plain HTTP/1.1, answering range requests unless
+    /// `ignore_ranges` is set. Requests without the expected authorization get a 403.
+    fn serve(data: Vec<u8>, ignore_ranges: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut range = None;
+                let mut authorized = false;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end().to_ascii_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("range: bytes=") {
+                        let (start, end) = value.split_once('-').unwrap();
+                        range = Some((start.parse().unwrap(), end.parse::<usize>().unwrap()));
+                    }
+                    authorized |= line == "authorization: secret";
+                }
+
+                let (status, extra, body) = match range {
+                    _ if !authorized => ("403 Forbidden", String::new(), Vec::new()),
+                    Some((start, _)) if start >= data.len() => (
+                        "416 Range Not Satisfiable",
+                        format!("Content-Range: bytes */{}\r\n", data.len()),
+                        Vec::new(),
+                    ),
+                    Some((start, end)) if !ignore_ranges => {
+                        let end = end.min(data.len() - 1);
+                        (
+                            "206 Partial Content",
+                            format!("Content-Range: bytes {}-{}/{}\r\n", start, end, data.len()),
+                            data[start..=end].to_vec(),
+                        )
+                    }
+                    _ => ("200 OK", String::new(), data.clone()),
+                };
+                let head = request_line.starts_with("HEAD");
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+                    status,
+                    body.len(),
+                    extra
+                )
+                .unwrap();
+                if !head {
+                    stream.write_all(&body).unwrap();
+                }
+            }
+        });
+        format!("http://{}/archive.ikvblob", addr)
+    }
+
+    fn memory(url: String) -> HttpMemory {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("secret"));
+        HttpMemory::new(url).with_headers(headers)
+    }
+
+    #[tokio::test]
+    async fn test_http_memory() {
+        let data = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let memory = memory(serve(data.clone(), false));
+
+        assert_eq!(memory.len().await.unwrap(), 1000);
+        for range in [0..10, 500..501, 990..1000, 0..1000, 10..10] {
+            assert_eq!(memory.read_slice(range.clone()).await.unwrap(), data[range]);
+        }
+        assert!(matches!(
+            memory.read_slice(1000..1010).await,
+            Err(MemoryError::OutOfBounds { len: 1000, .. })
+        ));
+        // The server cuts off reads past the end, which `read_exact` reports
+        assert!(matches!(
+            crate::memory_view::read_exact(&memory, 990..1010).await,
+            Err(MemoryError::ShortRead { got: 10, .. })
+        ));
+
+        let unauthorized = HttpMemory::new(memory.url());
+        assert!(matches!(
+            unauthorized.read_slice(0..10).await,
+            Err(MemoryError::Backend {
+                transient: false,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_http_memory_ignoring_ranges() {
+        let data = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let memory = memory(serve(data.clone(), true));
+
+        assert_eq!(memory.read_slice(0..1000).await.unwrap(), data);
+        match memory.read_slice(10..20).await {
+            Err(MemoryError::Backend { message, transient }) => {
+                assert!(message.contains("ignored the Range header"));
+                assert!(!transient);
+            }
+            other => panic!("Expected a backend error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_https_url() {
+        // A server that only takes the first byte the client sends, which over TLS starts the
+        // handshake record
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let first_byte = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut byte = [0];
+            stream.read_exact(&mut byte).ok().map(|()| byte[0])
+        });
+
+        let memory = HttpMemory::new(format!("https://{}/archive.ikvblob", addr));
+        assert!(memory.len().await.is_err());
+        // Unblocks the server if the client never connected
+        drop(TcpStream::connect(addr));
+        assert_eq!(first_byte.join().unwrap(), Some(0x16));
+    }
+
+    #[tokio::test]
+    async fn test_http_archive() {
+        let memory = memory(serve(build_test_file(100), false));
+        let view = IkvblobView::wrap(memory).await.unwrap();
+        for i in 0..100 {
+            assert_eq!(view.lookup(&test_key(i)).await.unwrap(), Some(vec![i as u8]));
+        }
+        view.verify(|_, _| {}).await.unwrap();
+    }
+}
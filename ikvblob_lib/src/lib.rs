@@ -1,13 +1,22 @@
-#![feature(array_chunks)]
 #![feature(iter_array_chunks)]
 #![feature(array_try_from_fn)]
+#![feature(return_type_notation)]
 
 pub mod utils;
 pub mod construction;
+pub mod builder;
 pub mod cuckoo;
 pub mod parametrized_hasher;
 pub mod index;
 pub mod fileformat_write;
 pub mod fileformat_read;
 pub mod memory_view;
-pub mod multihash;
\ No newline at end of file
+pub mod multihash;
+pub mod error;
+pub mod metadata;
+pub mod disk_cache;
+pub mod middleware;
+#[cfg(feature = "http")]
+pub mod http_memory;
+#[cfg(feature = "object-store")]
+pub mod object_store_memory;
@@ -1,18 +1,170 @@
-use std::ops::Range;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io,
+    ops::Range,
+    sync::{Arc, Mutex},
+};
 // use maybe_async::maybe_async;
+use futures::{
+    channel::oneshot,
+    future::{join_all, BoxFuture},
+};
 use memmap2::Mmap;
 
+/// Failure of a `Memory` backend to serve a read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryError {
+    /// The requested range is not contained in the memory.
+    OutOfBounds { range: Range<usize>, len: usize },
+    /// The backend returned fewer bytes than requested.
+    ShortRead { range: Range<usize>, got: usize },
+    /// The underlying storage returned an I/O error.
+    Io { kind: io::ErrorKind, message: String },
+    /// Any other backend failure, e.g. a rejected JS promise. `transient` tells whether retrying
+    /// the same read may succeed.
+    Backend { message: String, transient: bool },
+}
+
+impl MemoryError {
+    /// Whether the failed read may succeed if it is retried.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            MemoryError::OutOfBounds { .. } | MemoryError::ShortRead { .. } => false,
+            MemoryError::Io { kind, .. } => matches!(
+                kind,
+                io::ErrorKind::Interrupted
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+            ),
+            MemoryError::Backend { transient, .. } => *transient,
+        }
+    }
+}
+
+impl std::fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MemoryError::OutOfBounds { range, len } => {
+                write!(f, "Read of {:?} is out of bounds of memory of size {}", range, len)
+            }
+            MemoryError::ShortRead { range, got } => {
+                write!(f, "Read of {:?} returned only {} bytes", range, got)
+            }
+            MemoryError::Io { kind, message } => write!(f, "I/O error ({}): {}", kind, message),
+            MemoryError::Backend { message, .. } => write!(f, "Storage backend error: {}", message),
+        }
+    }
+}
+impl std::error::Error for MemoryError {}
+
+impl From<io::Error> for MemoryError {
+    fn from(e: io::Error) -> Self {
+        MemoryError::Io {
+            kind: e.kind(),
+            message: e.to_string(),
+        }
+    }
+}
+
+fn read_from_slice(slice: &[u8], range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+    match slice.get(range.clone()) {
+        Some(bytes) => Ok(bytes.to_vec()),
+        None => Err(MemoryError::OutOfBounds {
+            range,
+            len: slice.len(),
+        }),
+    }
+}
+
+/// Reads `range` and checks that the backend returned exactly the requested number of bytes.
+pub async fn read_exact<M: Memory>(
+    memory: &M,
+    range: Range<usize>,
+) -> Result<Vec<u8>, MemoryError> {
+    let bytes = memory.read_slice(range.clone()).await?;
+    if bytes.len() != range.len() {
+        return Err(MemoryError::ShortRead {
+            range,
+            got: bytes.len(),
+        });
+    }
+    Ok(bytes)
+}
+
+/// Reads all `ranges` with `Memory::read_slices` and checks that the backend returned exactly
+/// the requested number of bytes for each of them.
+pub async fn read_exact_many<M: Memory>(
+    memory: &M,
+    ranges: &[Range<usize>],
+) -> Result<Vec<Vec<u8>>, MemoryError> {
+    let slices = memory.read_slices(ranges).await?;
+    if slices.len() != ranges.len() {
+        return Err(MemoryError::Backend {
+            message: format!("Read of {} ranges returned {} slices", ranges.len(), slices.len()),
+            transient: false,
+        });
+    }
+    for (range, bytes) in ranges.iter().zip(&slices) {
+        if bytes.len() != range.len() {
+            return Err(MemoryError::ShortRead {
+                range: range.clone(),
+                got: bytes.len(),
+            });
+        }
+    }
+    Ok(slices)
+}
+
+/// Blocking counterpart of `read_exact`.
+pub fn read_exact_blocking<M: SyncMemory>(
+    memory: &M,
+    range: Range<usize>,
+) -> Result<Vec<u8>, MemoryError> {
+    let bytes = memory.read_slice_blocking(range.clone())?;
+    if bytes.len() != range.len() {
+        return Err(MemoryError::ShortRead {
+            range,
+            got: bytes.len(),
+        });
+    }
+    Ok(bytes)
+}
+
 // #[maybe_async::maybe_async(AFIT)]
 pub trait Memory {
-    // TODO add error handling
-    fn read_slice(&self, range: Range<usize>) -> impl std::future::Future<Output = Vec<u8>>;
-    fn len(&self) -> impl std::future::Future<Output = usize>;
+    fn read_slice(
+        &self,
+        range: Range<usize>,
+    ) -> impl std::future::Future<Output = Result<Vec<u8>, MemoryError>>;
+    fn len(&self) -> impl std::future::Future<Output = Result<usize, MemoryError>>;
+
+    /// Reads several ranges, returning their contents in the same order. The lookup methods of
+    /// `IkvblobView` read all ranges they need at once through this, so backends that can serve
+    /// several ranges with one operation should override it. By default, it issues one
+    /// `read_slice` per range, all of them concurrently.
+    fn read_slices(
+        &self,
+        ranges: &[Range<usize>],
+    ) -> impl std::future::Future<Output = Result<Vec<Vec<u8>>, MemoryError>> {
+        async move {
+            join_all(ranges.iter().map(|range| self.read_slice(range.clone())))
+                .await
+                .into_iter()
+                .collect()
+        }
+    }
 }
 
 // #[sync_impl]
 impl Memory for Vec<u8> {
-    async fn read_slice(&self, range: Range<usize>) -> Vec<u8> { (&self[range]).to_vec() }
-    async fn len(&self) -> usize { (*self).len() }
+    async fn read_slice(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        read_from_slice(self, range)
+    }
+    async fn len(&self) -> Result<usize, MemoryError> { Ok((*self).len()) }
 }
 
 pub struct MmapMemory {
@@ -21,6 +173,909 @@ pub struct MmapMemory {
 
 // #[sync_impl]
 impl Memory for MmapMemory {
-    async fn read_slice(&self, range: Range<usize>) -> Vec<u8> { (&self.mmap[range]).to_vec() }
-    async fn len(&self) -> usize { self.mmap.len() }
+    async fn read_slice(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        read_from_slice(&self.mmap, range)
+    }
+    async fn len(&self) -> Result<usize, MemoryError> { Ok(self.mmap.len()) }
+}
+
+/// Memory whose whole contents are available as one slice, e.g. a buffer or a memory map.
+/// Lookups in such memory can borrow values instead of copying them, see
+/// `IkvblobView::lookup_ref`.
+pub trait SliceMemory {
+    fn as_bytes(&self) -> &[u8];
+}
+
+impl SliceMemory for Vec<u8> {
+    fn as_bytes(&self) -> &[u8] { self }
+}
+
+impl SliceMemory for MmapMemory {
+    fn as_bytes(&self) -> &[u8] { &self.mmap }
+}
+
+/// Memory that can be read without an async runtime, e.g. a buffer, a memory map or a local
+/// file. Used by the blocking methods of `IkvblobView`.
+pub trait SyncMemory {
+    fn read_slice_blocking(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError>;
+    fn len_blocking(&self) -> Result<usize, MemoryError>;
+}
+
+impl SyncMemory for Vec<u8> {
+    fn read_slice_blocking(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        read_from_slice(self, range)
+    }
+    fn len_blocking(&self) -> Result<usize, MemoryError> { Ok((*self).len()) }
+}
+
+impl SyncMemory for MmapMemory {
+    fn read_slice_blocking(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        read_from_slice(&self.mmap, range)
+    }
+    fn len_blocking(&self) -> Result<usize, MemoryError> { Ok(self.mmap.len()) }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Seeks and reads on targets without positional reads, e.g. `wasm32-unknown-unknown`. This
+/// moves the file's cursor, so reads of the same file must not run concurrently there.
+#[cfg(not(any(unix, windows)))]
+fn read_exact_at(mut file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+/// Reads with positional reads (`pread` on Unix), so the file's cursor is never moved and a
+/// shared `&File` can serve reads from several threads. Other targets fall back to seeking.
+impl SyncMemory for File {
+    fn read_slice_blocking(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        let mut buf = vec![0; range.len()];
+        match read_exact_at(self, &mut buf, range.start as u64) {
+            Ok(()) => Ok(buf),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(MemoryError::OutOfBounds {
+                range,
+                len: self.len_blocking()?,
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+    fn len_blocking(&self) -> Result<usize, MemoryError> { Ok(self.metadata()?.len() as usize) }
+}
+
+/// A local file read with positional reads, for when `MmapMemory` isn't an option: on network
+/// file systems, on 32-bit targets where large archives don't fit into the address space, or
+/// when the file may be truncated while it is open, which turns reads of a mapping into SIGBUS
+/// crashes but reads of a file into errors.
+///
+/// Positional reads never move the file's cursor, so one `FileMemory`, or clones of it sharing
+/// the file handle, can serve any number of concurrent reads. Its async reads block the calling
+/// thread, see `TokioFileMemory` (feature `tokio`) for a variant that doesn't.
+#[derive(Debug, Clone)]
+pub struct FileMemory {
+    file: Arc<File>,
+    len: usize,
+}
+
+impl FileMemory {
+    pub fn new(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len() as usize;
+        Ok(FileMemory {
+            file: Arc::new(file),
+            len,
+        })
+    }
+
+    pub fn open(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        Self::new(File::open(path)?)
+    }
+}
+
+impl SyncMemory for FileMemory {
+    fn read_slice_blocking(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        if range.start > range.end || range.end > self.len {
+            return Err(MemoryError::OutOfBounds {
+                range,
+                len: self.len,
+            });
+        }
+        self.file.read_slice_blocking(range)
+    }
+    fn len_blocking(&self) -> Result<usize, MemoryError> { Ok(self.len) }
+}
+
+impl Memory for FileMemory {
+    async fn read_slice(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        self.read_slice_blocking(range)
+    }
+    async fn len(&self) -> Result<usize, MemoryError> { Ok(self.len) }
+}
+
+/// A `FileMemory` whose async reads run on tokio's blocking thread pool, so that they don't
+/// stall the runtime. Must be used from within a tokio runtime.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone)]
+pub struct TokioFileMemory {
+    inner: FileMemory,
+}
+
+#[cfg(feature = "tokio")]
+impl TokioFileMemory {
+    pub fn new(inner: FileMemory) -> Self { TokioFileMemory { inner } }
+
+    pub fn open(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        Ok(Self::new(FileMemory::open(path)?))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Memory for TokioFileMemory {
+    async fn read_slice(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || inner.read_slice_blocking(range))
+            .await
+            .map_err(|e| MemoryError::Backend {
+                message: format!("Blocking read failed: {}", e),
+                transient: false,
+            })?
+    }
+    async fn len(&self) -> Result<usize, MemoryError> { Ok(self.inner.len) }
+}
+
+/// Object-safe counterpart of `Memory`, for choosing a backend at runtime. Every `Memory`
+/// implements it, and `Box<dyn DynMemory>` implements `Memory` again, so an `IkvblobView` can
+/// be used with any backend without being generic over it, at the cost of one allocation per
+/// read. See `open_memory`.
+///
+/// It is `Send + Sync` with `Send` futures, so a `Box<dyn DynMemory>` can be shared between
+/// tasks of a multithreaded runtime. Only the `Memory` implementations whose futures are `Send`
+/// implement it.
+pub trait DynMemory: Send + Sync {
+    fn read_slice_dyn(&self, range: Range<usize>) -> BoxFuture<'_, Result<Vec<u8>, MemoryError>>;
+    fn len_dyn(&self) -> BoxFuture<'_, Result<usize, MemoryError>>;
+    fn read_slices_dyn<'a>(
+        &'a self,
+        ranges: &'a [Range<usize>],
+    ) -> BoxFuture<'a, Result<Vec<Vec<u8>>, MemoryError>>;
+}
+
+impl<M> DynMemory for M
+where
+    M: Memory<read_slice(..): Send, len(..): Send, read_slices(..): Send> + Send + Sync,
+{
+    fn read_slice_dyn(&self, range: Range<usize>) -> BoxFuture<'_, Result<Vec<u8>, MemoryError>> {
+        Box::pin(self.read_slice(range))
+    }
+    fn len_dyn(&self) -> BoxFuture<'_, Result<usize, MemoryError>> { Box::pin(self.len()) }
+    fn read_slices_dyn<'a>(
+        &'a self,
+        ranges: &'a [Range<usize>],
+    ) -> BoxFuture<'a, Result<Vec<Vec<u8>>, MemoryError>> {
+        Box::pin(self.read_slices(ranges))
+    }
+}
+
+impl Memory for Box<dyn DynMemory + '_> {
+    async fn read_slice(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        (**self).read_slice_dyn(range).await
+    }
+    async fn len(&self) -> Result<usize, MemoryError> { (**self).len_dyn().await }
+    async fn read_slices(&self, ranges: &[Range<usize>]) -> Result<Vec<Vec<u8>>, MemoryError> {
+        (**self).read_slices_dyn(ranges).await
+    }
+}
+
+/// Opens the archive at a location given as a string, choosing the backend by its scheme:
+///
+/// - a path, or a `file://` URL: `FileMemory`
+/// - `http://` and `https://` URLs: `HttpMemory` (feature `http`)
+/// - `s3://bucket/key` URLs: `ObjectStoreMemory::s3` (feature `s3`), configured through the
+///   `AWS_*` environment variables
+///
+/// Nothing is read yet, so a missing remote archive is only reported by the first read.
+pub fn open_memory(location: &str) -> Result<Box<dyn DynMemory>, MemoryError> {
+    let Some((scheme, _)) = location.split_once("://") else {
+        return Ok(Box::new(FileMemory::open(location)?));
+    };
+    match scheme {
+        "file" => Ok(Box::new(FileMemory::open(file_url_path(location)?)?)),
+        "http" | "https" => open_http(location),
+        "s3" => open_s3(location),
+        _ => Err(MemoryError::Backend {
+            message: format!("Unsupported scheme {:?} of {}", scheme, location),
+            transient: false,
+        }),
+    }
+}
+
+/// The local path of a `file://` URL, with percent-escapes decoded. The host must be empty or
+/// `localhost`.
+fn file_url_path(location: &str) -> Result<std::path::PathBuf, MemoryError> {
+    let invalid = || MemoryError::Backend {
+        message: format!("{} is not the URL of a local file", location),
+        transient: false,
+    };
+    let url = url::Url::parse(location).map_err(|_| invalid())?;
+    url.to_file_path().map_err(|_| invalid())
+}
+
+#[cfg(not(all(feature = "http", feature = "s3")))]
+fn missing_feature(location: &str, feature: &str) -> MemoryError {
+    MemoryError::Backend {
+        message: format!("Opening {} requires the `{}` feature", location, feature),
+        transient: false,
+    }
+}
+
+#[cfg(feature = "http")]
+fn open_http(location: &str) -> Result<Box<dyn DynMemory>, MemoryError> {
+    Ok(Box::new(crate::http_memory::HttpMemory::new(location)))
+}
+
+#[cfg(not(feature = "http"))]
+fn open_http(location: &str) -> Result<Box<dyn DynMemory>, MemoryError> {
+    Err(missing_feature(location, "http"))
+}
+
+#[cfg(feature = "s3")]
+fn open_s3(location: &str) -> Result<Box<dyn DynMemory>, MemoryError> {
+    let options = std::iter::empty::<(&str, String)>();
+    let memory = crate::object_store_memory::ObjectStoreMemory::s3(location, options)?;
+    Ok(Box::new(memory))
+}
+
+#[cfg(not(feature = "s3"))]
+fn open_s3(location: &str) -> Result<Box<dyn DynMemory>, MemoryError> {
+    Err(missing_feature(location, "s3"))
+}
+
+/// Hit and miss counts of a `CachedMemory`, in blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// Blocks served from the cache, including blocks that another read was already fetching.
+    pub hits: u64,
+    /// Blocks that had to be fetched from the inner memory.
+    pub misses: u64,
+    /// Blocks dropped from the cache to keep it within its capacity.
+    pub evictions: u64,
+}
+
+type BlockResult = Result<Arc<Vec<u8>>, MemoryError>;
+
+#[derive(Default)]
+struct CacheState {
+    len: Option<usize>,
+    // block index -> (data, last use)
+    blocks: HashMap<usize, (Arc<Vec<u8>>, u64)>,
+    // last use -> block index, oldest first
+    lru: BTreeMap<u64, usize>,
+    size: usize,
+    tick: u64,
+    // reads waiting for a block that is being fetched
+    in_flight: HashMap<usize, Vec<oneshot::Sender<BlockResult>>>,
+    stats: CacheStats,
+}
+
+impl CacheState {
+    fn get(&mut self, block: usize) -> Option<Arc<Vec<u8>>> {
+        let (data, last_use) = self.blocks.get_mut(&block)?;
+        self.lru.remove(last_use);
+        self.tick += 1;
+        *last_use = self.tick;
+        self.lru.insert(self.tick, block);
+        Some(data.clone())
+    }
+
+    fn insert(&mut self, block: usize, data: Arc<Vec<u8>>, capacity: usize) {
+        self.tick += 1;
+        self.size += data.len();
+        if let Some((old, last_use)) = self.blocks.insert(block, (data, self.tick)) {
+            self.size -= old.len();
+            self.lru.remove(&last_use);
+        }
+        self.lru.insert(self.tick, block);
+
+        while self.size > capacity {
+            let Some((_, oldest)) = self.lru.pop_first() else { break };
+            if let Some((data, _)) = self.blocks.remove(&oldest) {
+                self.size -= data.len();
+                self.stats.evictions += 1;
+            }
+        }
+    }
+}
+
+/// Groups sorted block indices into ranges of consecutive blocks.
+pub(crate) fn consecutive_runs(blocks: &[usize]) -> Vec<Range<usize>> {
+    let mut runs: Vec<Range<usize>> = Vec::new();
+    for &block in blocks {
+        match runs.last_mut() {
+            Some(run) if run.end == block => run.end += 1,
+            _ => runs.push(block..block + 1),
+        }
+    }
+    runs
+}
+
+/// Appends the part of `range` that lies in the block starting at `block_start` to `result`.
+pub(crate) fn extend_from_block(
+    result: &mut Vec<u8>,
+    range: &Range<usize>,
+    block_start: usize,
+    data: &[u8],
+) {
+    let from = range.start.max(block_start) - block_start;
+    let to = range.end.min(block_start + data.len()) - block_start;
+    result.extend_from_slice(&data[from..to]);
+}
+
+enum BlockSource {
+    Cached(Arc<Vec<u8>>),
+    Waiting(oneshot::Receiver<BlockResult>),
+    Fetching,
+}
+
+/// Caches the reads of another memory in fixed-size blocks aligned to multiples of the block
+/// size, keeping the most recently used blocks up to a total size. Reads that need several
+/// missing consecutive blocks fetch them with a single read, and a block that is already being
+/// fetched by another read is waited for instead of being fetched again. `read_slices` fetches
+/// the missing blocks of all its ranges with one `read_slices` of the inner memory.
+///
+/// Failed reads are not cached.
+pub struct CachedMemory<M> {
+    inner: M,
+    block_size: usize,
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+impl<M: Memory> CachedMemory<M> {
+    /// Caches up to `capacity` bytes of `inner` in blocks of `block_size` bytes.
+    pub fn new(inner: M, block_size: usize, capacity: usize) -> Self {
+        assert!(block_size > 0, "block size must be non-zero");
+        CachedMemory {
+            inner,
+            block_size,
+            capacity,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &M { &self.inner }
+
+    pub fn stats(&self) -> CacheStats { self.state.lock().unwrap().stats }
+
+    /// The bytes of the consecutive blocks `blocks` in a memory of size `len`.
+    fn byte_range(&self, blocks: &Range<usize>, len: usize) -> Range<usize> {
+        blocks.start * self.block_size..len.min(blocks.end * self.block_size)
+    }
+}
+
+/// Removes the in-flight markers of the blocks a read was fetching when the read is dropped, so
+/// that a cancelled read doesn't leave other reads waiting forever.
+struct InFlightGuard<'a> {
+    state: &'a Mutex<CacheState>,
+    blocks: Vec<usize>,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            for block in &self.blocks {
+                state.in_flight.remove(block);
+            }
+        }
+    }
+}
+
+impl<M: Memory> CachedMemory<M> {
+    /// The blocks that `range` overlaps.
+    fn blocks_of(&self, range: &Range<usize>) -> Range<usize> {
+        match range.is_empty() {
+            true => 0..0,
+            false => range.start / self.block_size..(range.end - 1) / self.block_size + 1,
+        }
+    }
+
+    /// Reads `ranges` block by block. The missing blocks of all of them are fetched with one
+    /// `read_slices` of the inner memory, one range per run of consecutive missing blocks.
+    async fn read_cached(&self, ranges: &[Range<usize>]) -> Result<Vec<Vec<u8>>, MemoryError> {
+        let len = self.len().await?;
+        if let Some(range) = ranges.iter().find(|r| r.start > r.end || r.end > len) {
+            return Err(MemoryError::OutOfBounds {
+                range: range.clone(),
+                len,
+            });
+        }
+        let mut blocks = ranges
+            .iter()
+            .flat_map(|range| self.blocks_of(range))
+            .collect::<Vec<_>>();
+        blocks.sort_unstable();
+        blocks.dedup();
+
+        let mut sources = Vec::with_capacity(blocks.len());
+        let mut to_fetch = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            for &block in &blocks {
+                if let Some(data) = state.get(block) {
+                    state.stats.hits += 1;
+                    sources.push(BlockSource::Cached(data));
+                } else if let Some(waiters) = state.in_flight.get_mut(&block) {
+                    let (sender, receiver) = oneshot::channel();
+                    waiters.push(sender);
+                    state.stats.hits += 1;
+                    sources.push(BlockSource::Waiting(receiver));
+                } else {
+                    state.in_flight.insert(block, Vec::new());
+                    state.stats.misses += 1;
+                    sources.push(BlockSource::Fetching);
+                    to_fetch.push(block);
+                }
+            }
+        }
+        let mut guard = InFlightGuard {
+            state: &self.state,
+            blocks: to_fetch.clone(),
+        };
+
+        // Fetch every run of consecutive missing blocks as one range of a single batch
+        let runs = consecutive_runs(&to_fetch);
+        let results = match runs.is_empty() {
+            true => Vec::new(),
+            false => {
+                let byte_ranges = runs
+                    .iter()
+                    .map(|run| self.byte_range(run, len))
+                    .collect::<Vec<_>>();
+                match read_exact_many(&self.inner, &byte_ranges).await {
+                    Ok(slices) => slices.into_iter().map(Ok).collect(),
+                    Err(e) => runs.iter().map(|_| Err(e.clone())).collect(),
+                }
+            }
+        };
+
+        let mut fetched = HashMap::new();
+        let mut error = None;
+        {
+            let mut state = self.state.lock().unwrap();
+            for (run, result) in runs.into_iter().zip(results) {
+                match result {
+                    Ok(bytes) => {
+                        for (block, chunk) in run.zip(bytes.chunks(self.block_size)) {
+                            let data = Arc::new(chunk.to_vec());
+                            state.insert(block, data.clone(), self.capacity);
+                            for waiter in state.in_flight.remove(&block).unwrap_or_default() {
+                                let _ = waiter.send(Ok(data.clone()));
+                            }
+                            fetched.insert(block, data);
+                        }
+                    }
+                    Err(e) => {
+                        for block in run {
+                            for waiter in state.in_flight.remove(&block).unwrap_or_default() {
+                                let _ = waiter.send(Err(e.clone()));
+                            }
+                        }
+                        error = Some(e);
+                    }
+                }
+            }
+            // The markers are gone now, and a later fetch of an evicted block sets new ones
+            guard.blocks.clear();
+        }
+        if let Some(e) = error {
+            return Err(e);
+        }
+
+        for (&block, source) in blocks.iter().zip(sources) {
+            let data = match source {
+                BlockSource::Cached(data) => data,
+                BlockSource::Fetching => continue,
+                BlockSource::Waiting(receiver) => receiver.await.unwrap_or_else(|_| {
+                    Err(MemoryError::Backend {
+                        message: "Cached read of this block was cancelled".to_string(),
+                        transient: true,
+                    })
+                })?,
+            };
+            fetched.insert(block, data);
+        }
+
+        let mut results = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            let mut result = Vec::with_capacity(range.len());
+            for block in self.blocks_of(range) {
+                let block_start = block * self.block_size;
+                extend_from_block(&mut result, range, block_start, &fetched[&block]);
+            }
+            results.push(result);
+        }
+        Ok(results)
+    }
+}
+
+impl<M: Memory> Memory for CachedMemory<M> {
+    async fn read_slice(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        Ok(self.read_cached(&[range]).await?.pop().unwrap())
+    }
+
+    async fn read_slices(&self, ranges: &[Range<usize>]) -> Result<Vec<Vec<u8>>, MemoryError> {
+        self.read_cached(ranges).await
+    }
+
+    async fn len(&self) -> Result<usize, MemoryError> {
+        if let Some(len) = self.state.lock().unwrap().len {
+            return Ok(len);
+        }
+        let len = self.inner.len().await?;
+        self.state.lock().unwrap().len = Some(len);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        collections::HashSet,
+        task::Poll,
+    };
+
+    use super::*;
+    use crate::fileformat_read::{
+        tests::{build_test_file, test_key},
+        IkvblobView,
+    };
+
+    /// Counts reads and batches of reads, and yields once inside every read so that the maximum
+    /// number of reads in flight at the same time can be observed. Shared by the tests of the
+    /// reader, the caches and the middleware.
+    #[derive(Default, Debug)]
+    pub(crate) struct CountingMemory {
+        pub(crate) inner: Vec<u8>,
+        pub(crate) reads: Cell<usize>,
+        pub(crate) batches: Cell<usize>,
+        pub(crate) fail_reads: Cell<bool>,
+        // reads starting at these offsets stay pending, and must be polled again once released
+        pub(crate) held: RefCell<HashSet<usize>>,
+        pub(crate) in_flight: Cell<usize>,
+        pub(crate) max_in_flight: Cell<usize>,
+    }
+
+    impl CountingMemory {
+        pub(crate) fn new(inner: Vec<u8>) -> Self {
+            CountingMemory {
+                inner,
+                ..Default::default()
+            }
+        }
+
+        pub(crate) fn reset(&self) {
+            self.reads.set(0);
+            self.batches.set(0);
+            self.max_in_flight.set(0);
+        }
+    }
+
+    impl Memory for CountingMemory {
+        async fn read_slice(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+            self.reads.set(self.reads.get() + 1);
+            self.in_flight.set(self.in_flight.get() + 1);
+            self.max_in_flight
+                .set(self.max_in_flight.get().max(self.in_flight.get()));
+            tokio::task::yield_now().await;
+            futures::future::poll_fn(|_| match self.held.borrow().contains(&range.start) {
+                true => Poll::Pending,
+                false => Poll::Ready(()),
+            })
+            .await;
+            self.in_flight.set(self.in_flight.get() - 1);
+            if self.fail_reads.get() {
+                return Err(MemoryError::Backend {
+                    message: "connection lost".to_string(),
+                    transient: true,
+                });
+            }
+            self.inner.read_slice(range).await
+        }
+        async fn read_slices(&self, ranges: &[Range<usize>]) -> Result<Vec<Vec<u8>>, MemoryError> {
+            self.batches.set(self.batches.get() + 1);
+            join_all(ranges.iter().map(|range| self.read_slice(range.clone())))
+                .await
+                .into_iter()
+                .collect()
+        }
+        async fn len(&self) -> Result<usize, MemoryError> { Ok(self.inner.len()) }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_open_memory() {
+        let archive = build_test_file(100);
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("test archive.ikvblob");
+        std::fs::write(&file, &archive).unwrap();
+
+        let path = file.to_str().unwrap();
+        let escaped = path.replace(' ', "%20");
+        for location in [
+            path.to_string(),
+            format!("file://{}", escaped),
+            format!("file://localhost{}", escaped),
+        ] {
+            let view = IkvblobView::wrap(open_memory(&location).unwrap()).await.unwrap();
+            assert_eq!(view.lookup(&test_key(7)).await.unwrap(), Some(vec![7]));
+        }
+
+        // Views of any backend can be moved to other threads
+        let view = IkvblobView::wrap(open_memory(path).unwrap()).await.unwrap();
+        let lookup = tokio::spawn(async move { view.lookup(&test_key(7)).await.unwrap() });
+        assert_eq!(lookup.await.unwrap(), Some(vec![7]));
+
+        // Different backends behind the same type
+        let memories: Vec<Box<dyn DynMemory>> = vec![
+            Box::new(archive.clone()),
+            Box::new(CachedMemory::new(archive.clone(), 64, 4)),
+            open_memory(path).unwrap(),
+        ];
+        for memory in &memories {
+            assert_eq!(memory.len().await.unwrap(), archive.len());
+            let slices = memory.read_slices(&[0..4, 10..20]).await.unwrap();
+            assert_eq!(slices, [&archive[0..4], &archive[10..20]]);
+        }
+
+        assert!(matches!(
+            open_memory(&format!("{}.missing", path)),
+            Err(MemoryError::Io {
+                kind: io::ErrorKind::NotFound,
+                ..
+            })
+        ));
+        assert!(matches!(
+            open_memory(&format!("file://example.com{}", escaped)),
+            Err(MemoryError::Backend {
+                transient: false,
+                ..
+            })
+        ));
+        assert!(matches!(
+            open_memory("ftp://example.com/archive"),
+            Err(MemoryError::Backend {
+                transient: false,
+                ..
+            })
+        ));
+        // Remote memories don't send any request until they are read
+        assert_eq!(
+            open_memory("https://example.com/archive").is_ok(),
+            cfg!(feature = "http")
+        );
+        assert_eq!(
+            open_memory("s3://bucket/archive").is_ok(),
+            cfg!(feature = "s3")
+        );
+    }
+
+    #[test]
+    fn test_file_memory() {
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), &data).unwrap();
+        let memory = FileMemory::open(file.path()).unwrap();
+
+        assert_eq!(memory.len_blocking().unwrap(), data.len());
+        assert_eq!(memory.read_slice_blocking(10..10).unwrap(), Vec::<u8>::new());
+        assert_eq!(
+            memory.read_slice_blocking(99_990..100_001),
+            Err(MemoryError::OutOfBounds {
+                range: 99_990..100_001,
+                len: 100_000
+            })
+        );
+
+        // Many threads share the file handle
+        std::thread::scope(|scope| {
+            for t in 0..8 {
+                let (memory, data) = (&memory, &data);
+                scope.spawn(move || {
+                    for i in 0..200 {
+                        let start = (t * 7919 + i * 104_729) % 99_000;
+                        let range = start..start + 1000;
+                        assert_eq!(memory.read_slice_blocking(range.clone()).unwrap(), data[range]);
+                    }
+                });
+            }
+        });
+
+        // A file truncated while it is open makes reads fail instead of crashing
+        file.as_file().set_len(50_000).unwrap();
+        assert!(matches!(
+            memory.read_slice_blocking(60_000..60_010),
+            Err(MemoryError::OutOfBounds { len: 50_000, .. })
+        ));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tokio_file_memory() {
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), &data).unwrap();
+        let memory = TokioFileMemory::open(file.path()).unwrap();
+
+        assert_eq!(memory.len().await.unwrap(), data.len());
+        let ranges = (0..100).map(|i| i * 997..i * 997 + 500).collect::<Vec<_>>();
+        let results = join_all(ranges.iter().map(|r| memory.read_slice(r.clone()))).await;
+        for (range, result) in ranges.into_iter().zip(results) {
+            assert_eq!(result.unwrap(), data[range]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_memory() {
+        let data = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let memory = CachedMemory::new(
+            CountingMemory::new(data.clone()),
+            100,
+            10_000,
+        );
+        let reads = || memory.inner().reads.get();
+
+        for range in [0..10, 95..105, 150..450, 990..1000, 999..1000, 500..500, 0..1000] {
+            assert_eq!(memory.read_slice(range.clone()).await.unwrap(), data[range]);
+        }
+        // Runs of consecutive missing blocks are fetched with one read
+        assert_eq!(reads(), 5);
+        assert_eq!(memory.stats().misses, 10);
+        // Everything is cached now
+        assert_eq!(memory.read_slice(0..1000).await.unwrap(), data);
+        assert_eq!(reads(), 5);
+
+        assert_eq!(
+            memory.read_slice(990..1001).await,
+            Err(MemoryError::OutOfBounds {
+                range: 990..1001,
+                len: 1000
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cached_memory_batches() {
+        let data = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let memory = CachedMemory::new(
+            CountingMemory::new(data.clone()),
+            100,
+            10_000,
+        );
+        let reads = || memory.inner().reads.get();
+        let batches = || memory.inner().batches.get();
+
+        // The missing blocks of all ranges are fetched as one batch, one range per run
+        let ranges = [450..460, 0..10, 95..105, 470..480, 700..700, 990..1000];
+        let slices = memory.read_slices(&ranges).await.unwrap();
+        for (range, slice) in ranges.iter().zip(&slices) {
+            assert_eq!(slice[..], data[range.clone()]);
+        }
+        assert_eq!((batches(), reads()), (1, 3));
+        assert_eq!(memory.stats().misses, 4);
+
+        // Ranges that are cached entirely don't need a batch
+        let slices = memory.read_slices(&[5..15, 455..475]).await.unwrap();
+        assert_eq!(slices, vec![data[5..15].to_vec(), data[455..475].to_vec()]);
+        assert_eq!((batches(), reads()), (1, 3));
+        assert!(matches!(
+            memory.read_slices(&[0..10, 999..1001]).await,
+            Err(MemoryError::OutOfBounds { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cached_memory_dedup_and_eviction() {
+        let data = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let memory = CachedMemory::new(
+            CountingMemory::new(data.clone()),
+            100,
+            250,
+        );
+        let reads = || memory.inner().reads.get();
+
+        // Concurrent reads of the same block share one fetch
+        let results = join_all([
+            memory.read_slice(0..10),
+            memory.read_slice(20..30),
+            memory.read_slice(5..150),
+        ])
+        .await;
+        assert_eq!(results[0].as_deref(), Ok(&data[0..10]));
+        assert_eq!(results[1].as_deref(), Ok(&data[20..30]));
+        assert_eq!(results[2].as_deref(), Ok(&data[5..150]));
+        assert_eq!(reads(), 2);
+        assert_eq!(
+            memory.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 2,
+                evictions: 0
+            }
+        );
+
+        // Only two blocks fit, so reading a third one evicts the least recently used one
+        memory.read_slice(0..10).await.unwrap();
+        memory.read_slice(200..210).await.unwrap();
+        assert_eq!(memory.stats().evictions, 1);
+        assert_eq!(reads(), 3);
+        memory.read_slice(0..10).await.unwrap();
+        assert_eq!(reads(), 3);
+        memory.read_slice(100..110).await.unwrap();
+        assert_eq!(reads(), 4);
+
+        // Failed reads aren't cached, and are reported to every read waiting for the block
+        memory.inner().fail_reads.set(true);
+        let results = join_all([memory.read_slice(500..510), memory.read_slice(505..520)]).await;
+        assert!(results.iter().all(|r| matches!(r, Err(MemoryError::Backend { .. }))));
+        memory.inner().fail_reads.set(false);
+        assert_eq!(memory.read_slice(500..510).await.unwrap(), data[500..510]);
+    }
+
+    #[tokio::test]
+    async fn test_cached_memory_refetch_while_waiting() {
+        let data = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let memory = CachedMemory::new(
+            CountingMemory::new(data.clone()),
+            100,
+            100,
+        );
+        let held = |start: usize, hold: bool| {
+            let mut held = memory.inner().held.borrow_mut();
+            if hold { held.insert(start) } else { held.remove(&start) }
+        };
+
+        // `first` fetches block 0 itself and waits for `second` to fetch block 1
+        held(100, true);
+        let mut second = std::pin::pin!(memory.read_slice(100..110));
+        assert!(futures::poll!(&mut second).is_pending());
+        let mut first = std::pin::pin!(memory.read_slice(0..110));
+        for _ in 0..3 {
+            assert!(futures::poll!(&mut first).is_pending());
+        }
+
+        // Meanwhile, block 0 is evicted and fetched again for two other reads
+        memory.read_slice(300..310).await.unwrap();
+        held(0, true);
+        let mut refetch = std::pin::pin!(memory.read_slice(0..10));
+        assert!(futures::poll!(&mut refetch).is_pending());
+        let mut waiting = std::pin::pin!(memory.read_slice(5..15));
+        assert!(futures::poll!(&mut waiting).is_pending());
+
+        // Finishing `first` must not drop the markers of the new fetch of block 0
+        held(100, false);
+        assert_eq!((&mut second).await.unwrap(), data[100..110]);
+        assert_eq!((&mut first).await.unwrap(), data[0..110]);
+        held(0, false);
+        assert_eq!((&mut refetch).await.unwrap(), data[0..10]);
+        assert_eq!((&mut waiting).await.unwrap(), data[5..15]);
+        assert_eq!(memory.stats().misses, 4);
+    }
 }
@@ -0,0 +1,249 @@
+//! Dynamic metadata section
+//!
+//! Every IkvBlob carries a CBOR map with string keys. A few keys are reserved because the
+//! implementation itself uses them (see `RESERVED_KEYS`). All other keys belong to the user, who
+//! can store things like a description of the archive or a schema for the values there.
+
+use std::collections::BTreeMap;
+
+use ciborium::Value;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::IkvblobError;
+
+pub const COMPRESSION_TYPE_KEY: &str = "compression_type";
+pub const COMPRESSION_DICT_KEY: &str = "compression_dict";
+/// Number of regions the index is split into, see `cuckoo::region_of`. Only archives of format
+/// version 3 are split, and it is ignored in older ones.
+pub const INDEX_PARTITIONS_KEY: &str = "index_partitions";
+
+/// Metadata keys used by the implementation. Users can't write them, and they are kept apart
+/// from user keys when reading.
+pub const RESERVED_KEYS: &[&str] = &[
+    COMPRESSION_TYPE_KEY,
+    COMPRESSION_DICT_KEY,
+    INDEX_PARTITIONS_KEY,
+];
+
+pub fn is_reserved_key(key: &str) -> bool { RESERVED_KEYS.contains(&key) }
+
+/// The decoded dynamic metadata of an archive.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DynamicMetadata {
+    entries: BTreeMap<String, Value>,
+}
+
+impl DynamicMetadata {
+    /// Decodes the CBOR-encoded metadata section.
+    pub fn decode(bytes: &[u8]) -> Result<Self, IkvblobError> {
+        let md_cbor = ciborium::from_reader::<Value, _>(bytes)
+            .map_err(|e| IkvblobError::MetadataDecode(e.to_string()))?;
+        let md_map = match md_cbor {
+            Value::Map(m) => m,
+            _ => return Err(IkvblobError::MetadataDecode("Metadata is not a map".to_string())),
+        };
+
+        let mut entries = BTreeMap::new();
+        for (k, v) in md_map {
+            let k = match k {
+                Value::Text(k) => k,
+                _ => {
+                    return Err(IkvblobError::MetadataDecode(
+                        "Metadata key is not a string".to_string(),
+                    ))
+                }
+            };
+            if entries.contains_key(&k) {
+                return Err(IkvblobError::MetadataDecode(format!(
+                    "Duplicate metadata key: {}",
+                    k
+                )));
+            }
+            entries.insert(k, v);
+        }
+        Ok(DynamicMetadata { entries })
+    }
+
+    /// Builds metadata for writing from a user-provided value, which must serialize to a map
+    /// with distinct string keys (or to nothing, e.g. `()`, for no user metadata). Reserved keys
+    /// are rejected.
+    pub fn from_user<T: Serialize + ?Sized>(user_metadata: &T) -> Result<Self, IkvblobError> {
+        let value = Value::serialized(user_metadata)
+            .map_err(|e| IkvblobError::MetadataEncode(e.to_string()))?;
+        let map = match value {
+            Value::Null => Vec::new(),
+            Value::Map(m) => m,
+            _ => {
+                return Err(IkvblobError::MetadataEncode(
+                    "User metadata is not a map".to_string(),
+                ))
+            }
+        };
+
+        let mut entries = BTreeMap::new();
+        for (k, v) in map {
+            let k = match k {
+                Value::Text(k) => k,
+                _ => {
+                    return Err(IkvblobError::MetadataEncode(
+                        "Metadata key is not a string".to_string(),
+                    ))
+                }
+            };
+            if is_reserved_key(&k) {
+                return Err(IkvblobError::ReservedMetadataKey(k));
+            }
+            if entries.contains_key(&k) {
+                return Err(IkvblobError::MetadataEncode(format!(
+                    "Duplicate metadata key: {}",
+                    k
+                )));
+            }
+            entries.insert(k, v);
+        }
+        Ok(DynamicMetadata { entries })
+    }
+
+    /// Sets a reserved key. Only the writer should call this.
+    pub(crate) fn set_reserved(&mut self, key: &str, value: Value) {
+        debug_assert!(is_reserved_key(key));
+        self.entries.insert(key.to_string(), value);
+    }
+
+    /// Encodes the metadata as a CBOR map, the way it is stored in the archive.
+    pub fn encode(&self) -> Result<Vec<u8>, IkvblobError> {
+        let map = Value::Map(
+            self.entries
+                .iter()
+                .map(|(k, v)| (Value::Text(k.clone()), v.clone()))
+                .collect(),
+        );
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&map, &mut bytes)
+            .map_err(|e| IkvblobError::MetadataEncode(e.to_string()))?;
+        Ok(bytes)
+    }
+
+    /// Returns the value of a user key. Reserved keys are never returned, see `reserved`.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        if is_reserved_key(key) {
+            return None;
+        }
+        self.entries.get(key)
+    }
+
+    /// Deserializes the value of a user key into `T`.
+    pub fn get_as<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, IkvblobError> {
+        self.get(key)
+            .map(|v| {
+                v.deserialized().map_err(|e| {
+                    IkvblobError::MetadataDecode(format!("Can't deserialize key {}: {}", key, e))
+                })
+            })
+            .transpose()
+    }
+
+    /// Lists the user keys, in sorted order.
+    pub fn user_keys(&self) -> impl Iterator<Item = &str> {
+        self.entries
+            .keys()
+            .map(|k| k.as_str())
+            .filter(|k| !is_reserved_key(k))
+    }
+
+    /// Returns the value of a reserved key.
+    pub fn reserved(&self, key: &str) -> Option<&Value> {
+        if !is_reserved_key(key) {
+            return None;
+        }
+        self.entries.get(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ciborium::cbor;
+
+    use super::*;
+
+    #[test]
+    fn test_decode_metadata() {
+        let mut bytes = Vec::new();
+        let md = cbor!({
+            "compression_type" => "zstd",
+            "description" => "test archive",
+            "schema" => { "version" => 3, "fields" => ["a", "b"] },
+        })
+        .unwrap();
+        ciborium::into_writer(&md, &mut bytes).unwrap();
+        let md = DynamicMetadata::decode(&bytes).unwrap();
+
+        assert_eq!(md.user_keys().collect::<Vec<_>>(), vec!["description", "schema"]);
+        assert_eq!(md.get("description"), Some(&Value::Text("test archive".into())));
+        assert_eq!(md.get("compression_type"), None);
+        assert_eq!(md.reserved("compression_type"), Some(&Value::Text("zstd".into())));
+        assert_eq!(md.reserved("description"), None);
+
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        struct Schema {
+            version: u32,
+            fields: Vec<String>,
+        }
+        assert_eq!(
+            md.get_as::<Schema>("schema").unwrap(),
+            Some(Schema {
+                version: 3,
+                fields: vec!["a".into(), "b".into()]
+            })
+        );
+        assert_eq!(md.get_as::<Schema>("missing").unwrap(), None);
+        assert!(matches!(
+            md.get_as::<Schema>("description"),
+            Err(IkvblobError::MetadataDecode(_))
+        ));
+
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&cbor!({ 1 => 2 }).unwrap(), &mut bytes).unwrap();
+        assert!(matches!(
+            DynamicMetadata::decode(&bytes),
+            Err(IkvblobError::MetadataDecode(_))
+        ));
+    }
+
+    #[test]
+    fn test_user_metadata() {
+        #[derive(serde::Serialize)]
+        struct Info {
+            description: String,
+            schema_version: u32,
+        }
+        let info = Info {
+            description: "test archive".into(),
+            schema_version: 2,
+        };
+        let mut md = DynamicMetadata::from_user(&info).unwrap();
+        md.set_reserved(COMPRESSION_TYPE_KEY, "zstd".into());
+        let md = DynamicMetadata::decode(&md.encode().unwrap()).unwrap();
+        assert_eq!(md.user_keys().collect::<Vec<_>>(), vec!["description", "schema_version"]);
+        assert_eq!(md.get_as::<u32>("schema_version").unwrap(), Some(2));
+        assert_eq!(md.reserved(COMPRESSION_TYPE_KEY), Some(&Value::Text("zstd".into())));
+
+        assert_eq!(DynamicMetadata::from_user(&()).unwrap(), DynamicMetadata::default());
+        assert!(matches!(
+            DynamicMetadata::from_user(&cbor!({ "compression_dict" => 1 }).unwrap()),
+            Err(IkvblobError::ReservedMetadataKey(k)) if k == "compression_dict"
+        ));
+        assert!(matches!(
+            DynamicMetadata::from_user(&[1, 2, 3]),
+            Err(IkvblobError::MetadataEncode(_))
+        ));
+        let duplicate = Value::Map(vec![
+            (Value::Text("description".into()), Value::Text("first".into())),
+            (Value::Text("description".into()), Value::Text("second".into())),
+        ]);
+        assert!(matches!(
+            DynamicMetadata::from_user(&duplicate),
+            Err(IkvblobError::MetadataEncode(_))
+        ));
+    }
+}
@@ -0,0 +1,792 @@
+//! Memory wrappers that make remote reads more reliable
+//!
+//! - `RetryMemory` retries reads that failed with a transient error, with exponential backoff.
+//! - `TimeoutMemory` fails reads that take too long with a transient `TimedOut` error.
+//! - `HedgedMemory` sends a second request when a read takes longer than most recent reads did,
+//!   and takes whichever answer arrives first.
+//! - `InstrumentedMemory` counts requests and bytes and records request latencies, see
+//!   `MemoryMetrics`.
+//!
+//! They compose like any other memory. A typical stack for a remote archive is
+//! `RetryMemory::new(TimeoutMemory::new(HedgedMemory::new(remote, ..), ..), ..)`, so that a
+//! timed out read is retried. Every wrapper passes a batch of reads (`Memory::read_slices`) on
+//! as one batch, and retries, times out or hedges it as a whole.
+//!
+//! Waiting goes through a `Clock`, so the wrappers work with any async runtime (see
+//! `TokioClock`, feature `tokio`), and tests can drive them with a fake clock.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    ops::Range,
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::future::{select, Either};
+
+use crate::memory_view::{Memory, MemoryError};
+
+/// Source of time for the wrappers in this module.
+pub trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()>;
+}
+
+/// Clock of the tokio runtime. Must be used from within a tokio runtime.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+#[cfg(feature = "tokio")]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant { tokio::time::Instant::now().into_std() }
+    async fn sleep(&self, duration: Duration) { tokio::time::sleep(duration).await }
+}
+
+/// How `RetryMemory` retries failed reads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt.
+    pub max_retries: usize,
+    /// Wait before the first retry.
+    pub initial_backoff: Duration,
+    /// Every further wait is this many times longer than the one before.
+    pub multiplier: f64,
+    /// Upper bound on a single wait.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Retries reads and length queries that fail with a transient error (see
+/// `MemoryError::is_transient`). Other errors, and the last error once the retries are used up,
+/// are returned as they are.
+#[derive(Debug)]
+pub struct RetryMemory<M, C> {
+    inner: M,
+    clock: C,
+    policy: RetryPolicy,
+}
+
+impl<M: Memory, C: Clock> RetryMemory<M, C> {
+    pub fn new(inner: M, clock: C, policy: RetryPolicy) -> Self {
+        RetryMemory {
+            inner,
+            clock,
+            policy,
+        }
+    }
+
+    pub fn inner(&self) -> &M { &self.inner }
+
+    async fn retry<T, F: Future<Output = Result<T, MemoryError>>>(
+        &self,
+        mut attempt: impl FnMut() -> F,
+    ) -> Result<T, MemoryError> {
+        let mut backoff = self.policy.initial_backoff;
+        let mut retries = 0;
+        loop {
+            match attempt().await {
+                Err(e) if e.is_transient() && retries < self.policy.max_retries => {
+                    log::debug!("Retrying after transient error: {}", e);
+                    self.clock.sleep(backoff).await;
+                    backoff = backoff
+                        .mul_f64(self.policy.multiplier)
+                        .min(self.policy.max_backoff);
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<M: Memory, C: Clock> Memory for RetryMemory<M, C> {
+    async fn read_slice(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        self.retry(|| self.inner.read_slice(range.clone())).await
+    }
+    async fn len(&self) -> Result<usize, MemoryError> { self.retry(|| self.inner.len()).await }
+    async fn read_slices(&self, ranges: &[Range<usize>]) -> Result<Vec<Vec<u8>>, MemoryError> {
+        self.retry(|| self.inner.read_slices(ranges)).await
+    }
+}
+
+/// Fails reads and length queries that take longer than a timeout with
+/// `MemoryError::Io { kind: TimedOut, .. }`, which is transient.
+#[derive(Debug)]
+pub struct TimeoutMemory<M, C> {
+    inner: M,
+    clock: C,
+    timeout: Duration,
+}
+
+impl<M: Memory, C: Clock> TimeoutMemory<M, C> {
+    pub fn new(inner: M, clock: C, timeout: Duration) -> Self {
+        TimeoutMemory {
+            inner,
+            clock,
+            timeout,
+        }
+    }
+
+    pub fn inner(&self) -> &M { &self.inner }
+
+    async fn with_timeout<T>(
+        &self,
+        what: String,
+        future: impl Future<Output = Result<T, MemoryError>>,
+    ) -> Result<T, MemoryError> {
+        match select(pin!(future), pin!(self.clock.sleep(self.timeout))).await {
+            Either::Left((result, _)) => result,
+            Either::Right(((), _)) => Err(MemoryError::Io {
+                kind: io::ErrorKind::TimedOut,
+                message: format!("{} timed out after {:?}", what, self.timeout),
+            }),
+        }
+    }
+}
+
+impl<M: Memory, C: Clock> Memory for TimeoutMemory<M, C> {
+    async fn read_slice(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        let what = format!("Read of {:?}", range);
+        self.with_timeout(what, self.inner.read_slice(range)).await
+    }
+    async fn len(&self) -> Result<usize, MemoryError> {
+        self.with_timeout("Length query".to_string(), self.inner.len())
+            .await
+    }
+    async fn read_slices(&self, ranges: &[Range<usize>]) -> Result<Vec<Vec<u8>>, MemoryError> {
+        let what = format!("Read of {} ranges", ranges.len());
+        self.with_timeout(what, self.inner.read_slices(ranges))
+            .await
+    }
+}
+
+/// When `HedgedMemory` sends a second request for a read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HedgePolicy {
+    /// A read that has taken longer than this fraction of recent reads gets a second request,
+    /// e.g. 0.95 to hedge the slowest 5%.
+    pub percentile: f64,
+    /// Number of recent reads whose latencies are kept.
+    pub window: usize,
+    /// Delay before the second request while no latencies have been recorded yet.
+    pub initial_delay: Duration,
+}
+
+impl Default for HedgePolicy {
+    fn default() -> Self {
+        HedgePolicy {
+            percentile: 0.95,
+            window: 100,
+            initial_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Sends a second, identical request when a read hasn't completed after the `percentile`
+/// latency of recent reads, and returns whichever answer arrives first. An error only wins if
+/// the other request fails too. This trades a few percent of extra requests for a much shorter
+/// tail latency. Length queries aren't hedged. Batches of reads are hedged as a whole, and
+/// their latencies count like the ones of single reads.
+#[derive(Debug)]
+pub struct HedgedMemory<M, C> {
+    inner: M,
+    clock: C,
+    policy: HedgePolicy,
+    latencies: Mutex<VecDeque<Duration>>,
+}
+
+impl<M: Memory, C: Clock> HedgedMemory<M, C> {
+    pub fn new(inner: M, clock: C, policy: HedgePolicy) -> Self {
+        HedgedMemory {
+            inner,
+            clock,
+            policy,
+            latencies: Mutex::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &M { &self.inner }
+
+    /// How long a read may take before it is hedged.
+    pub fn hedge_delay(&self) -> Duration {
+        let latencies = self.latencies.lock().unwrap();
+        if latencies.is_empty() {
+            return self.policy.initial_delay;
+        }
+        let mut sorted = latencies.iter().copied().collect::<Vec<_>>();
+        sorted.sort_unstable();
+        let rank = (self.policy.percentile * sorted.len() as f64).ceil() as usize;
+        sorted[rank.clamp(1, sorted.len()) - 1]
+    }
+
+    fn record(&self, latency: Duration) {
+        let mut latencies = self.latencies.lock().unwrap();
+        if latencies.len() >= self.policy.window.max(1) {
+            latencies.pop_front();
+        }
+        latencies.push_back(latency);
+    }
+
+    async fn timed<T>(
+        &self,
+        request: impl Future<Output = Result<T, MemoryError>>,
+    ) -> Result<T, MemoryError> {
+        let start = self.clock.now();
+        let result = request.await;
+        if result.is_ok() {
+            self.record(self.clock.now().saturating_duration_since(start));
+        }
+        result
+    }
+
+    async fn hedged<T, F: Future<Output = Result<T, MemoryError>>>(
+        &self,
+        what: impl std::fmt::Display,
+        request: impl Fn() -> F,
+    ) -> Result<T, MemoryError> {
+        let mut primary = pin!(self.timed(request()));
+        let delay = self.hedge_delay();
+        match select(primary.as_mut(), pin!(self.clock.sleep(delay))).await {
+            Either::Left((result, _)) => return result,
+            Either::Right(((), _)) => {}
+        }
+
+        log::debug!("Hedging {} after {:?}", what, delay);
+        let hedge = pin!(self.timed(request()));
+        match select(primary, hedge).await {
+            Either::Left((Err(_), other)) | Either::Right((Err(_), other)) => other.await,
+            Either::Left((result, _)) | Either::Right((result, _)) => result,
+        }
+    }
+}
+
+impl<M: Memory, C: Clock> Memory for HedgedMemory<M, C> {
+    async fn read_slice(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        let what = format!("read of {:?}", range);
+        self.hedged(what, || self.inner.read_slice(range.clone()))
+            .await
+    }
+    async fn len(&self) -> Result<usize, MemoryError> { self.inner.len().await }
+    async fn read_slices(&self, ranges: &[Range<usize>]) -> Result<Vec<Vec<u8>>, MemoryError> {
+        let what = format!("read of {} ranges", ranges.len());
+        self.hedged(what, || self.inner.read_slices(ranges)).await
+    }
+}
+
+/// Upper bounds of the buckets of the latency histogram of `MemoryMetrics`. The last bucket,
+/// which has no bound, counts slower requests.
+pub const LATENCY_BUCKETS: [Duration; 12] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_millis(2500),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+];
+
+/// Counters of an `InstrumentedMemory`, which can be shared with a metrics exporter.
+///
+/// A request is a call of `read_slice`, `read_slices` or `len` of the memory. A batch of reads
+/// is a single request, as backends such as `ObjectStoreMemory` serve it together.
+#[derive(Debug, Default)]
+pub struct MemoryMetrics {
+    requests: AtomicU64,
+    reads: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+    latency_counts: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_sum_nanos: AtomicU64,
+}
+
+/// The values of `MemoryMetrics` at one point in time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryMetricsSnapshot {
+    /// Number of requests, see `MemoryMetrics`.
+    pub requests: u64,
+    /// Number of byte ranges read, including the ones of failed requests.
+    pub reads: u64,
+    /// Number of bytes returned by successful reads.
+    pub bytes: u64,
+    /// Number of failed requests.
+    pub errors: u64,
+    /// Number of requests per bucket of `LATENCY_BUCKETS`, plus one for slower requests. These
+    /// aren't cumulative, unlike the buckets of a Prometheus histogram.
+    pub latency_counts: Vec<u64>,
+    /// Total latency of all requests.
+    pub latency_sum: Duration,
+}
+
+impl MemoryMetrics {
+    pub fn snapshot(&self) -> MemoryMetricsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        MemoryMetricsSnapshot {
+            requests: load(&self.requests),
+            reads: load(&self.reads),
+            bytes: load(&self.bytes),
+            errors: load(&self.errors),
+            latency_counts: self.latency_counts.iter().map(load).collect(),
+            latency_sum: Duration::from_nanos(load(&self.latency_sum_nanos)),
+        }
+    }
+
+    fn record(&self, reads: usize, bytes: usize, ok: bool, latency: Duration) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.reads.fetch_add(reads as u64, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        if !ok {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        let bucket = LATENCY_BUCKETS.partition_point(|bound| *bound < latency);
+        self.latency_counts[bucket].fetch_add(1, Ordering::Relaxed);
+        let nanos = latency.as_nanos().min(u64::MAX as u128) as u64;
+        self.latency_sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+impl MemoryMetricsSnapshot {
+    /// Number of requests that took at most the given bound of `LATENCY_BUCKETS`, in the
+    /// cumulative form of Prometheus histograms.
+    pub fn latency_count_le(&self, bound: Duration) -> u64 {
+        let buckets = LATENCY_BUCKETS.partition_point(|b| *b <= bound);
+        self.latency_counts[..buckets].iter().sum()
+    }
+}
+
+/// Records the number of requests, bytes and errors of a memory and the latency of its
+/// requests in a `MemoryMetrics`, e.g. to find out how many reads a lookup costs.
+#[derive(Debug)]
+pub struct InstrumentedMemory<M, C> {
+    inner: M,
+    clock: C,
+    metrics: Arc<MemoryMetrics>,
+}
+
+impl<M: Memory, C: Clock> InstrumentedMemory<M, C> {
+    pub fn new(inner: M, clock: C) -> Self { Self::with_metrics(inner, clock, Arc::default()) }
+
+    /// Records into existing metrics, e.g. to sum up the requests of several archives.
+    pub fn with_metrics(inner: M, clock: C, metrics: Arc<MemoryMetrics>) -> Self {
+        InstrumentedMemory {
+            inner,
+            clock,
+            metrics,
+        }
+    }
+
+    pub fn inner(&self) -> &M { &self.inner }
+
+    /// The metrics of this memory. The handle stays valid after the memory has been moved into
+    /// an `IkvblobView`.
+    pub fn metrics(&self) -> Arc<MemoryMetrics> { self.metrics.clone() }
+
+    async fn timed<T>(
+        &self,
+        reads: usize,
+        bytes: impl FnOnce(&T) -> usize,
+        future: impl Future<Output = Result<T, MemoryError>>,
+    ) -> Result<T, MemoryError> {
+        let start = self.clock.now();
+        let result = future.await;
+        let latency = self.clock.now().saturating_duration_since(start);
+        let bytes = result.as_ref().map_or(0, bytes);
+        self.metrics.record(reads, bytes, result.is_ok(), latency);
+        result
+    }
+}
+
+impl<M: Memory, C: Clock> Memory for InstrumentedMemory<M, C> {
+    async fn read_slice(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        self.timed(1, Vec::len, self.inner.read_slice(range)).await
+    }
+    async fn len(&self) -> Result<usize, MemoryError> {
+        self.timed(0, |_| 0, self.inner.len()).await
+    }
+    async fn read_slices(&self, ranges: &[Range<usize>]) -> Result<Vec<Vec<u8>>, MemoryError> {
+        let bytes = |slices: &Vec<Vec<u8>>| slices.iter().map(Vec::len).sum();
+        self.timed(ranges.len(), bytes, self.inner.read_slices(ranges))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        pin::Pin,
+        sync::atomic::AtomicBool,
+        task::{Context, Poll, Wake, Waker},
+    };
+
+    use super::*;
+    use crate::{
+        fileformat_read::{
+            tests::{build_test_file, test_key},
+            IkvblobView,
+        },
+        memory_view::tests::CountingMemory,
+    };
+
+    /// A clock whose time only moves when `run` finds that nothing but sleeps are pending, and
+    /// then jumps to the earliest deadline. This makes timings exact and tests instantaneous.
+    /// Futures that wake themselves, e.g. to yield, are polled again without moving the time.
+    struct FakeClock {
+        start: Instant,
+        elapsed: Cell<Duration>,
+        sleepers: RefCell<Vec<(Duration, Waker)>>,
+    }
+
+    impl FakeClock {
+        fn new() -> Self {
+            FakeClock {
+                start: Instant::now(),
+                elapsed: Cell::new(Duration::ZERO),
+                sleepers: RefCell::default(),
+            }
+        }
+
+        fn elapsed(&self) -> Duration { self.elapsed.get() }
+
+        fn run<T>(&self, future: impl Future<Output = T>) -> T {
+            let mut future = pin!(future);
+            let woken = Arc::new(WakeFlag::default());
+            let waker = Waker::from(woken.clone());
+            let mut cx = Context::from_waker(&waker);
+            loop {
+                if let Poll::Ready(result) = future.as_mut().poll(&mut cx) {
+                    return result;
+                }
+                if woken.0.swap(false, Ordering::Relaxed) {
+                    continue;
+                }
+                let mut sleepers = self.sleepers.borrow_mut();
+                let next = sleepers.iter().map(|(deadline, _)| *deadline).min();
+                self.elapsed.set(next.expect("future is stuck"));
+                sleepers.retain(|(deadline, waker)| {
+                    let due = *deadline <= self.elapsed.get();
+                    if due {
+                        waker.wake_by_ref();
+                    }
+                    !due
+                });
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct WakeFlag(AtomicBool);
+
+    impl Wake for WakeFlag {
+        fn wake(self: Arc<Self>) { self.0.store(true, Ordering::Relaxed) }
+    }
+
+    struct Sleep<'a> {
+        clock: &'a FakeClock,
+        deadline: Duration,
+    }
+
+    impl Future for Sleep<'_> {
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.clock.elapsed() >= self.deadline {
+                return Poll::Ready(());
+            }
+            let sleeper = (self.deadline, cx.waker().clone());
+            self.clock.sleepers.borrow_mut().push(sleeper);
+            Poll::Pending
+        }
+    }
+
+    impl Clock for &FakeClock {
+        fn now(&self) -> Instant { self.start + self.elapsed() }
+        fn sleep(&self, duration: Duration) -> impl Future<Output = ()> {
+            Sleep {
+                clock: self,
+                deadline: self.elapsed() + duration,
+            }
+        }
+    }
+
+    /// Answers every read, or batch of reads, of `counts` after the latency of the next
+    /// scripted step, failing it if the step says so. Reads past the end of the script take 10ms
+    /// and succeed.
+    struct ScriptedMemory<'a> {
+        clock: &'a FakeClock,
+        counts: CountingMemory,
+        script: RefCell<VecDeque<(u64, Option<MemoryError>)>>,
+    }
+
+    impl<'a> ScriptedMemory<'a> {
+        fn new(clock: &'a FakeClock, script: Vec<(u64, Option<MemoryError>)>) -> Self {
+            ScriptedMemory {
+                clock,
+                counts: CountingMemory::new((0..100).collect()),
+                script: RefCell::new(script.into()),
+            }
+        }
+    }
+
+    impl ScriptedMemory<'_> {
+        async fn step(&self) -> Result<(), MemoryError> {
+            let step = self.script.borrow_mut().pop_front();
+            let (latency, error) = step.unwrap_or((10, None));
+            self.clock.sleep(Duration::from_millis(latency)).await;
+            error.map_or(Ok(()), Err)
+        }
+    }
+
+    impl Memory for ScriptedMemory<'_> {
+        async fn read_slice(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+            let slice = self.counts.read_slice(range).await;
+            self.step().await?;
+            slice
+        }
+        async fn len(&self) -> Result<usize, MemoryError> { self.counts.len().await }
+        async fn read_slices(&self, ranges: &[Range<usize>]) -> Result<Vec<Vec<u8>>, MemoryError> {
+            let slices = self.counts.read_slices(ranges).await;
+            self.step().await?;
+            slices
+        }
+    }
+
+    fn transient() -> Option<MemoryError> {
+        Some(MemoryError::Backend {
+            message: "connection reset".to_string(),
+            transient: true,
+        })
+    }
+
+    fn permanent() -> Option<MemoryError> {
+        Some(MemoryError::Backend {
+            message: "access denied".to_string(),
+            transient: false,
+        })
+    }
+
+    fn ms(ms: u64) -> Duration { Duration::from_millis(ms) }
+
+    #[test]
+    fn test_retry() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            initial_backoff: ms(100),
+            multiplier: 2.0,
+            max_backoff: ms(300),
+        };
+
+        // Two failures, then success after waiting 100ms and 200ms
+        let clock = FakeClock::new();
+        let script = vec![(10, transient()), (10, transient()), (10, None)];
+        let memory = RetryMemory::new(ScriptedMemory::new(&clock, script), &clock, policy.clone());
+        assert_eq!(clock.run(memory.read_slice(0..3)), Ok(vec![0, 1, 2]));
+        assert_eq!(memory.inner().counts.reads.get(), 3);
+        assert_eq!(clock.elapsed(), ms(10 + 100 + 10 + 200 + 10));
+
+        // The backoff is capped, and the last error is returned once the retries are used up
+        let clock = FakeClock::new();
+        let script = vec![(10, transient()); 4];
+        let memory = RetryMemory::new(ScriptedMemory::new(&clock, script), &clock, policy.clone());
+        assert_eq!(
+            clock.run(memory.read_slice(0..3)),
+            Err(transient().unwrap())
+        );
+        assert_eq!(memory.inner().counts.reads.get(), 4);
+        assert_eq!(clock.elapsed(), ms(4 * 10 + 100 + 200 + 300));
+
+        // Permanent errors aren't retried
+        let clock = FakeClock::new();
+        let script = vec![(10, permanent())];
+        let memory = RetryMemory::new(ScriptedMemory::new(&clock, script), &clock, policy);
+        assert_eq!(
+            clock.run(memory.read_slice(0..3)),
+            Err(permanent().unwrap())
+        );
+        assert_eq!(memory.inner().counts.reads.get(), 1);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let clock = FakeClock::new();
+        let script = vec![(5000, None), (10, None)];
+        let memory = TimeoutMemory::new(ScriptedMemory::new(&clock, script), &clock, ms(1000));
+        match clock.run(memory.read_slice(0..3)) {
+            Err(e @ MemoryError::Io { kind, .. }) => {
+                assert_eq!(kind, io::ErrorKind::TimedOut);
+                assert!(e.is_transient());
+            }
+            other => panic!("Expected a timeout, got {:?}", other),
+        }
+        assert_eq!(clock.elapsed(), ms(1000));
+        assert_eq!(clock.run(memory.read_slice(0..3)), Ok(vec![0, 1, 2]));
+
+        // A timed out read is retried
+        let clock = FakeClock::new();
+        let script = vec![(5000, None), (10, None)];
+        let memory = RetryMemory::new(
+            TimeoutMemory::new(ScriptedMemory::new(&clock, script), &clock, ms(1000)),
+            &clock,
+            RetryPolicy::default(),
+        );
+        assert_eq!(clock.run(memory.read_slice(0..3)), Ok(vec![0, 1, 2]));
+        assert_eq!(clock.elapsed(), ms(1000 + 100 + 10));
+    }
+
+    #[test]
+    fn test_hedging() {
+        let clock = FakeClock::new();
+        // 20 reads taking 29 down to 10ms, then a read that stalls, whose hedge is fast
+        let mut script = (0..20).map(|i| (29 - i, None)).collect::<Vec<_>>();
+        script.extend([(5000, None), (5, None)]);
+        let policy = HedgePolicy {
+            percentile: 0.9,
+            window: 20,
+            initial_delay: ms(50),
+        };
+        let memory = HedgedMemory::new(ScriptedMemory::new(&clock, script), &clock, policy);
+        assert_eq!(memory.hedge_delay(), ms(50));
+
+        for _ in 0..20 {
+            clock.run(memory.read_slice(0..3)).unwrap();
+        }
+        // Every read was faster than all reads before it, so none was hedged
+        assert_eq!(memory.inner().counts.reads.get(), 20);
+        assert_eq!(memory.hedge_delay(), ms(27));
+
+        let start = clock.elapsed();
+        assert_eq!(clock.run(memory.read_slice(0..3)), Ok(vec![0, 1, 2]));
+        assert_eq!(memory.inner().counts.reads.get(), 22);
+        assert_eq!(clock.elapsed() - start, ms(27 + 5));
+
+        // A failed request doesn't win over a slower successful one
+        let clock = FakeClock::new();
+        let script = vec![(150, None), (10, transient())];
+        let memory = HedgedMemory::new(
+            ScriptedMemory::new(&clock, script),
+            &clock,
+            HedgePolicy::default(),
+        );
+        assert_eq!(clock.run(memory.read_slice(0..3)), Ok(vec![0, 1, 2]));
+        assert_eq!(clock.elapsed(), ms(150));
+        assert_eq!(memory.inner().counts.reads.get(), 2);
+    }
+
+    #[test]
+    fn test_batches() {
+        // Batches are passed on as they are, and retried, timed out or hedged as a whole
+        let ranges = [0..3, 10..12];
+        let slices = Ok(vec![vec![0, 1, 2], vec![10, 11]]);
+
+        let clock = FakeClock::new();
+        let script = vec![(10, transient()), (10, None)];
+        let policy = RetryPolicy::default();
+        let memory = RetryMemory::new(ScriptedMemory::new(&clock, script), &clock, policy);
+        assert_eq!(clock.run(memory.read_slices(&ranges)), slices);
+        assert_eq!(memory.inner().counts.batches.get(), 2);
+        assert_eq!(clock.elapsed(), ms(10 + 100 + 10));
+
+        let clock = FakeClock::new();
+        let script = vec![(5000, None), (10, None)];
+        let memory = TimeoutMemory::new(ScriptedMemory::new(&clock, script), &clock, ms(1000));
+        assert!(matches!(
+            clock.run(memory.read_slices(&ranges)),
+            Err(MemoryError::Io {
+                kind: io::ErrorKind::TimedOut,
+                ..
+            })
+        ));
+        assert_eq!(clock.run(memory.read_slices(&ranges)), slices);
+        assert_eq!(memory.inner().counts.batches.get(), 2);
+
+        let clock = FakeClock::new();
+        let script = vec![(5000, None), (5, None)];
+        let policy = HedgePolicy::default();
+        let memory = HedgedMemory::new(ScriptedMemory::new(&clock, script), &clock, policy);
+        assert_eq!(clock.run(memory.read_slices(&ranges)), slices);
+        assert_eq!(clock.elapsed(), ms(100 + 5));
+        assert_eq!(memory.inner().counts.batches.get(), 2);
+        assert_eq!(memory.inner().counts.reads.get(), 4);
+    }
+
+    #[test]
+    fn test_instrumented_memory() {
+        let clock = FakeClock::new();
+        let script = vec![
+            (3, None),
+            (200, None),
+            (10, permanent()),
+            (20_000, None),
+            (7, None),
+        ];
+        let memory = InstrumentedMemory::new(ScriptedMemory::new(&clock, script), &clock);
+        let metrics = memory.metrics();
+        assert_eq!(
+            metrics.snapshot(),
+            MemoryMetricsSnapshot {
+                latency_counts: vec![0; LATENCY_BUCKETS.len() + 1],
+                ..Default::default()
+            }
+        );
+
+        clock.run(memory.read_slice(0..10)).unwrap();
+        clock.run(memory.read_slice(10..15)).unwrap();
+        clock.run(memory.read_slice(0..10)).unwrap_err();
+        clock.run(memory.read_slice(0..100)).unwrap();
+        clock.run(memory.len()).unwrap();
+        // A batch is a single request, taking 7ms
+        clock.run(memory.read_slices(&[0..1, 1..3])).unwrap();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.requests, 6);
+        assert_eq!(snapshot.reads, 6);
+        assert_eq!(snapshot.bytes, 10 + 5 + 100 + 3);
+        assert_eq!(snapshot.errors, 1);
+        assert_eq!(
+            snapshot.latency_counts,
+            [1, 1, 2, 0, 0, 0, 1, 0, 0, 0, 0, 0, 1]
+        );
+        assert_eq!(snapshot.latency_sum, ms(3 + 200 + 10 + 20_000 + 7));
+        assert_eq!(snapshot.latency_count_le(ms(10)), 4);
+        assert_eq!(snapshot.latency_count_le(ms(12)), 4);
+        assert_eq!(snapshot.latency_count_le(Duration::from_secs(10)), 5);
+    }
+
+    #[test]
+    fn test_instrumented_archive() {
+        let clock = FakeClock::new();
+        let memory = InstrumentedMemory::new(build_test_file(100), &clock);
+        let metrics = memory.metrics();
+        let view = clock
+            .run(IkvblobView::<_, _, (u64, u64)>::wrap(memory))
+            .unwrap();
+        let wrap_requests = metrics.snapshot().requests;
+
+        let key = test_key(7);
+        assert_eq!(clock.run(view.lookup(&key)).unwrap(), Some(vec![7]));
+        // One batch of bucket reads, then the value
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.requests - wrap_requests, 2);
+        assert_eq!(snapshot.errors, 0);
+    }
+}
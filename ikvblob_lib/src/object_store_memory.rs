@@ -0,0 +1,387 @@
+//! Object store backend
+//!
+//! `ObjectStoreMemory` reads an archive stored as a single object in any store supported by the
+//! `object_store` crate. With the `s3` feature this includes S3 and S3-compatible stores such as
+//! MinIO, with SigV4-signed requests.
+
+use std::{ops::Range, sync::Arc};
+
+use futures::lock::Mutex;
+use object_store::{path::Path, ObjectMeta, ObjectStore, ObjectStoreExt};
+
+use crate::memory_view::{Memory, MemoryError};
+
+/// Reads the HTTP status out of the message of a failed request. `object_store` keeps it in an
+/// error type of its own that isn't public, so the message is the only way to get at it.
+fn http_status(message: &str) -> Option<u16> {
+    let (_, status) = message.split_once("status code: ")?;
+    status.get(..3)?.parse().ok()
+}
+
+/// Whether a request that failed with `e` may succeed if it is retried. Network failures and
+/// error responses of remote stores end up as `Generic`, along with permanent failures such as
+/// bad requests, invalid configuration or malformed responses. So only failed connections,
+/// timeouts and the statuses of overloaded or failing servers count as transient.
+fn is_transient(e: &object_store::Error) -> bool {
+    let object_store::Error::Generic { source, .. } = e else {
+        return false;
+    };
+    let mut next: Option<&(dyn std::error::Error + 'static)> = Some(&**source);
+    while let Some(error) = next {
+        #[cfg(feature = "s3")]
+        if let Some(e) = error.downcast_ref::<object_store::client::HttpError>() {
+            use object_store::client::HttpErrorKind::*;
+            return matches!(e.kind(), Connect | Request | Timeout | Interrupted);
+        }
+        if let Some(e) = error.downcast_ref::<std::io::Error>() {
+            return MemoryError::from(std::io::Error::from(e.kind())).is_transient();
+        }
+        if let Some(status) = http_status(&error.to_string()) {
+            return status >= 500 || status == 429 || status == 408;
+        }
+        next = error.source();
+    }
+    false
+}
+
+fn store_error(e: object_store::Error) -> MemoryError {
+    MemoryError::Backend {
+        transient: is_transient(&e),
+        message: format!("Object store error: {}", e),
+    }
+}
+
+/// An archive stored as an object, see the module documentation.
+///
+/// The metadata of the object is fetched with a single `HEAD` request, which is then cached for
+/// the lifetime of the memory.
+#[derive(Debug)]
+pub struct ObjectStoreMemory {
+    store: Arc<dyn ObjectStore>,
+    path: Path,
+    meta: Mutex<Option<ObjectMeta>>,
+}
+
+impl ObjectStoreMemory {
+    pub fn new(store: Arc<dyn ObjectStore>, path: Path) -> Self {
+        ObjectStoreMemory {
+            store,
+            path,
+            meta: Mutex::new(None),
+        }
+    }
+
+    /// Opens an object in S3 or an S3-compatible store from a URL like
+    /// `s3://bucket/path/to/archive`. Credentials, region and endpoint are read from the usual
+    /// `AWS_*` environment variables, and `options` (e.g. `("endpoint", "http://localhost:9000")`)
+    /// take precedence over them. See `object_store::aws::AmazonS3ConfigKey` for all options.
+    #[cfg(feature = "s3")]
+    pub fn s3<K: AsRef<str>, V: Into<String>>(
+        url: &str,
+        options: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<Self, MemoryError> {
+        use object_store::aws::AmazonS3Builder;
+
+        let invalid = |message: String| MemoryError::Backend {
+            message,
+            transient: false,
+        };
+        let mut builder = AmazonS3Builder::from_env().with_url(url);
+        for (key, value) in options {
+            let key = key
+                .as_ref()
+                .parse()
+                .map_err(|e: object_store::Error| invalid(e.to_string()))?;
+            builder = builder.with_config(key, value);
+        }
+        let key = url
+            .split_once("://")
+            .and_then(|(_, rest)| rest.split_once('/'))
+            .map_or("", |(_, key)| key);
+        let path = Path::from_url_path(key).map_err(|e| invalid(e.to_string()))?;
+        Ok(Self::new(Arc::new(builder.build().map_err(store_error)?), path))
+    }
+
+    pub fn path(&self) -> &Path { &self.path }
+
+    /// The metadata of the object. Its `e_tag` can serve as the identity of the archive for a
+    /// `DiskCachedMemory`.
+    pub async fn meta(&self) -> Result<ObjectMeta, MemoryError> {
+        let mut meta = self.meta.lock().await;
+        if meta.is_none() {
+            *meta = Some(self.store.head(&self.path).await.map_err(store_error)?);
+        }
+        Ok(meta.clone().unwrap())
+    }
+}
+
+impl Memory for ObjectStoreMemory {
+    async fn read_slice(&self, range: Range<usize>) -> Result<Vec<u8>, MemoryError> {
+        let len = self.len().await?;
+        if range.start > range.end || range.end > len {
+            return Err(MemoryError::OutOfBounds { range, len });
+        }
+        if range.is_empty() {
+            return Ok(Vec::new());
+        }
+        let bytes = self
+            .store
+            .get_range(&self.path, range.start as u64..range.end as u64)
+            .await
+            .map_err(store_error)?;
+        Ok(bytes.to_vec())
+    }
+
+    async fn len(&self) -> Result<usize, MemoryError> { Ok(self.meta().await?.size as usize) }
+
+    /// Uses `ObjectStore::get_ranges`, which merges nearby ranges into fewer requests.
+    async fn read_slices(&self, ranges: &[Range<usize>]) -> Result<Vec<Vec<u8>>, MemoryError> {
+        let len = self.len().await?;
+        if let Some(range) = ranges.iter().find(|r| r.start > r.end || r.end > len) {
+            return Err(MemoryError::OutOfBounds {
+                range: range.clone(),
+                len,
+            });
+        }
+        // Stores reject empty ranges
+        let non_empty = ranges
+            .iter()
+            .filter(|r| !r.is_empty())
+            .map(|r| r.start as u64..r.end as u64)
+            .collect::<Vec<_>>();
+        let mut slices = self
+            .store
+            .get_ranges(&self.path, &non_empty)
+            .await
+            .map_err(store_error)?
+            .into_iter();
+        Ok(ranges
+            .iter()
+            .map(|r| {
+                if r.is_empty() {
+                    Vec::new()
+                } else {
+                    slices.next().map_or_else(Vec::new, |bytes| bytes.to_vec())
+                }
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use object_store::{memory::InMemory, PutPayload};
+
+    use super::*;
+    use crate::fileformat_read::{
+        tests::{build_test_file, test_key},
+        IkvblobView,
+    };
+
+    async fn check_archive(memory: ObjectStoreMemory) {
+        let view = IkvblobView::wrap(memory).await.unwrap();
+        for i in 0..100 {
+            assert_eq!(view.lookup(&test_key(i)).await.unwrap(), Some(vec![i as u8]));
+        }
+        view.verify(|_, _| {}).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_object_store_memory() {
+        let store = Arc::new(InMemory::new());
+        let data = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let path = Path::from("archives/test.ikvblob");
+        store
+            .put(&path, PutPayload::from(data.clone()))
+            .await
+            .unwrap();
+
+        let memory = ObjectStoreMemory::new(store.clone(), path);
+        assert_eq!(memory.len().await.unwrap(), 1000);
+        assert!(memory.meta().await.unwrap().e_tag.is_some());
+        let ranges = [0..10, 500..501, 990..1000, 0..1000, 10..10];
+        for range in ranges.clone() {
+            assert_eq!(memory.read_slice(range.clone()).await.unwrap(), data[range]);
+        }
+        let slices = memory.read_slices(&ranges).await.unwrap();
+        for (range, slice) in ranges.into_iter().zip(slices) {
+            assert_eq!(slice, data[range]);
+        }
+        assert_eq!(
+            memory.read_slice(990..1001).await,
+            Err(MemoryError::OutOfBounds {
+                range: 990..1001,
+                len: 1000
+            })
+        );
+
+        let missing = ObjectStoreMemory::new(store.clone(), Path::from("missing"));
+        assert!(matches!(
+            missing.len().await,
+            Err(MemoryError::Backend {
+                transient: false,
+                ..
+            })
+        ));
+
+        let path = Path::from("archives/archive.ikvblob");
+        store
+            .put(&path, PutPayload::from(build_test_file(100)))
+            .await
+            .unwrap();
+        check_archive(ObjectStoreMemory::new(store, path)).await;
+    }
+
+    #[test]
+    fn test_store_errors() {
+        let generic = |source: Box<dyn std::error::Error + Send + Sync>| {
+            is_transient(&object_store::Error::Generic { store: "S3", source })
+        };
+        let status = |status: &str| {
+            format!(
+                "Error performing GET http://localhost/bucket/archive in 1s - Server returned \
+                 non-2xx status code: {}: <Error></Error>",
+                status
+            )
+        };
+        assert!(generic(status("503 Service Unavailable").into()));
+        assert!(generic(status("429 Too Many Requests").into()));
+        assert!(!generic(status("400 Bad Request").into()));
+        assert!(generic(Box::new(std::io::Error::from(std::io::ErrorKind::ConnectionReset))));
+        assert!(!generic(Box::new(std::io::Error::from(std::io::ErrorKind::InvalidData))));
+        assert!(!generic("Invalid configuration: missing region".into()));
+        assert!(!is_transient(&object_store::Error::NotFound {
+            path: "archive".to_string(),
+            source: "Not found".into(),
+        }));
+    }
+
+    #[cfg(feature = "s3")]
+    #[test]
+    fn test_http_errors() {
+        use object_store::client::{HttpError, HttpErrorKind};
+
+        /// Stands in for the errors of the stores, which wrap the errors of their requests
+        #[derive(Debug)]
+        struct RequestFailed(HttpError);
+        impl std::fmt::Display for RequestFailed {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "Request failed: {}", self.0)
+            }
+        }
+        impl std::error::Error for RequestFailed {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> { Some(&self.0) }
+        }
+
+        let http = |kind| {
+            let error = HttpError::new(kind, std::io::Error::other("failed"));
+            is_transient(&object_store::Error::Generic {
+                store: "S3",
+                source: Box::new(RequestFailed(error)),
+            })
+        };
+        assert!(http(HttpErrorKind::Connect));
+        assert!(http(HttpErrorKind::Timeout));
+        assert!(!http(HttpErrorKind::Decode));
+        assert!(!http(HttpErrorKind::Unknown));
+    }
+
+    /// Serves `data` as the object `bucket/archive.ikvblob` of a minimal S3 API, rejecting any
+    /// request that isn't SigV4-signed with the access key `test-key`.
+    #[cfg(feature = "s3")]
+    fn serve_s3(data: Vec<u8>) -> (String, Arc<std::sync::atomic::AtomicUsize>) {
+        use std::{
+            io::{BufRead, BufReader, Write},
+            net::TcpListener,
+            sync::atomic::{AtomicUsize, Ordering},
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut range = None;
+                let mut signed = false;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end().to_ascii_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("range: bytes=") {
+                        let (start, end) = value.split_once('-').unwrap();
+                        range = Some((start.parse().unwrap(), end.parse::<usize>().unwrap()));
+                    }
+                    signed |= line
+                        .starts_with("authorization: aws4-hmac-sha256 credential=test-key/");
+                }
+
+                let path = request_line.split(' ').nth(1).unwrap();
+                let (status, extra, body) = match range {
+                    _ if !signed => ("403 Forbidden", String::new(), Vec::new()),
+                    _ if path != "/bucket/archive.ikvblob" => {
+                        ("404 Not Found", String::new(), Vec::new())
+                    }
+                    Some((start, end)) => (
+                        "206 Partial Content",
+                        format!("Content-Range: bytes {}-{}/{}\r\n", start, end, data.len()),
+                        data[start..=end].to_vec(),
+                    ),
+                    None => ("200 OK", String::new(), data.clone()),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\n{}ETag: \"v1\"\r\n\
+                     Last-Modified: Mon, 01 Jan 2024 00:00:00 GMT\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len(),
+                    extra
+                )
+                .unwrap();
+                if !request_line.starts_with("HEAD") {
+                    stream.write_all(&body).unwrap();
+                }
+            }
+        });
+        (format!("http://{}", addr), requests)
+    }
+
+    #[cfg(feature = "s3")]
+    #[tokio::test]
+    async fn test_s3_memory() {
+        let (endpoint, requests) = serve_s3(build_test_file(100));
+        let options = [
+            ("endpoint", endpoint.as_str()),
+            ("allow_http", "true"),
+            ("region", "us-east-1"),
+            ("access_key_id", "test-key"),
+            ("secret_access_key", "test-secret"),
+        ];
+        let memory = ObjectStoreMemory::s3("s3://bucket/archive.ikvblob", options).unwrap();
+        assert_eq!(memory.meta().await.unwrap().e_tag.as_deref(), Some("\"v1\""));
+        // The HEAD request is only sent once
+        memory.len().await.unwrap();
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+        check_archive(memory).await;
+
+        let unsigned = ObjectStoreMemory::s3(
+            "s3://bucket/archive.ikvblob",
+            [
+                ("endpoint", endpoint.as_str()),
+                ("allow_http", "true"),
+                ("region", "us-east-1"),
+                ("skip_signature", "true"),
+            ],
+        )
+        .unwrap();
+        assert!(matches!(unsigned.len().await, Err(MemoryError::Backend { .. })));
+    }
+}
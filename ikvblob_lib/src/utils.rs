@@ -2,6 +2,7 @@ use std::{
     collections::HashMap,
     fs::File,
     io::{self, BufRead, Read},
+    ops::Range,
     sync::mpsc,
 };
 
@@ -70,6 +71,10 @@ impl <W: io::Write> CRC32Writer<W> {
     pub fn current_crc(&mut self) -> u32 {
         self.crc.clone().finalize()
     }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
 }
 
 impl <W: io::Write> io::Write for CRC32Writer<W> {
@@ -137,3 +142,59 @@ pub fn recover_order<T>(rx: mpsc::Receiver<(usize, T)>, mut op: impl FnMut(T)) {
 
     info!("Buffer capacity used: {}", buffer.capacity());
 }
+
+
+/// Sorts the given byte ranges and merges the ones that overlap or are separated by at most
+/// `max_gap` bytes. Reading the merged ranges covers every input range with as few reads as
+/// possible, at the cost of fetching the (small) gaps in between.
+pub fn coalesce_ranges(mut ranges: Vec<Range<usize>>, max_gap: usize) -> Vec<Range<usize>> {
+    ranges.sort_by_key(|r| (r.start, r.end));
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for r in ranges {
+        match merged.last_mut() {
+            Some(last) if r.start <= last.end.saturating_add(max_gap) => {
+                last.end = last.end.max(r.end);
+            }
+            _ => merged.push(r),
+        }
+    }
+    merged
+}
+
+/// Given the output of `coalesce_ranges` and the bytes read for each merged range, returns the
+/// bytes of `range`, which must be contained in one of the merged ranges.
+pub fn slice_from_coalesced<'a>(
+    merged: &[Range<usize>],
+    data: &'a [Vec<u8>],
+    range: Range<usize>,
+) -> Option<&'a [u8]> {
+    let i = merged.partition_point(|m| m.start <= range.start).checked_sub(1)?;
+    let m = &merged[i];
+    if range.end > m.end {
+        return None;
+    }
+    data[i].get(range.start - m.start..range.end - m.start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coalesce_ranges() {
+        let ranges = vec![20..30, 0..10, 5..12, 40..50, 100..110];
+        assert_eq!(coalesce_ranges(ranges.clone(), 0), vec![0..12, 20..30, 40..50, 100..110]);
+        assert_eq!(coalesce_ranges(ranges.clone(), 10), vec![0..50, 100..110]);
+
+        let merged = coalesce_ranges(ranges.clone(), 10);
+        let data = merged
+            .iter()
+            .map(|r| r.clone().map(|x| x as u8).collect())
+            .collect::<Vec<Vec<u8>>>();
+        for r in ranges {
+            let expected = r.clone().map(|x| x as u8).collect::<Vec<_>>();
+            assert_eq!(slice_from_coalesced(&merged, &data, r), Some(&expected[..]));
+        }
+        assert_eq!(slice_from_coalesced(&merged, &data, 45..60), None);
+    }
+}